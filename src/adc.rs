@@ -3,7 +3,10 @@
 // SingularitySurfer 2021
 
 use byteorder::{BigEndian, ByteOrder};
+use heapless::Vec;
 use log::{info, warn};
+use miniconf::Miniconf;
+use serde::{Deserialize, Serialize};
 
 use stm32_eth::hal::{
     gpio::{gpiob::*, Alternate, Output, PushPull, AF5},
@@ -15,7 +18,7 @@ use stm32_eth::hal::{
     time::MegaHertz,
 };
 
-use crate::AdcFilterSettings;
+use crate::{AdcCalibration, AdcFilterSettings, AuxChannelSettings};
 
 /// SPI Mode 3
 pub const SPI_MODE: spi::Mode = spi::Mode {
@@ -25,9 +28,73 @@ pub const SPI_MODE: spi::Mode = spi::Mode {
 
 pub const SPI_CLOCK: MegaHertz = MegaHertz(2);
 
+/// Number of times a transaction with a checksum mismatch is retried.
+const MAX_RETRIES: u32 = 3;
+
+/// IFMODE CRC_EN setting enabling CRC checksums on register reads and writes.
+const IFMODE_CRC_EN: u32 = 0b10 << 2;
+
+/// IFMODE DATA_STAT bit appending the status register to the data register.
+const IFMODE_DATA_STAT: u32 = 1 << 6;
+
+/// ADCMODE operating mode field.
+const ADCMODE_MODE_SHIFT: u32 = 4;
+const ADCMODE_MODE_MASK: u32 = 0b111 << ADCMODE_MODE_SHIFT;
+const MODE_CONTINUOUS: u32 = 0b000;
+const MODE_SINGLE: u32 = 0b001;
+const MODE_STANDBY: u32 = 0b010;
+
+/// CHx channel enable bit.
+const CH_EN: u32 = 1 << 15;
+
+/// CHx setup selection and analog input fields.
+const CH_SETUP_SHIFT: u32 = 12;
+const CH_AINPOS_SHIFT: u32 = 5;
+const CH_AIN_MASK: u32 = 0b11111;
+
+/// The first ADC channel used for auxiliary measurements.
+const AUX_CHANNEL: u8 = 2;
+/// The ADC channel used for diagnostic measurements. It is shared with the second auxiliary
+/// channel.
+pub const DIAGNOSTIC_CHANNEL: u8 = 3;
+
+/// Output data rates of the ODR codes of the filter registers in SPS.
+const ODR: [f32; 23] = [
+    31250.0, 31250.0, 31250.0, 31250.0, 31250.0, 31250.0, 15625.0, 10417.0, 5208.0, 2597.0, 1007.0,
+    503.8, 381.0, 200.3, 100.2, 59.52, 49.68, 20.01, 16.63, 10.0, 5.0, 2.5, 1.25,
+];
+
+/// Conservative margin in s for the settling of the filter after switching to the next channel in
+/// addition to the output data period.
+const SETTLING_MARGIN: f32 = 200e-6;
+
+/// Number of conversion sequences after the diagnostic channel was configured until a diagnostic
+/// measurement without result is abandoned. The diagnostic channel is converted in the first or
+/// second sequence, depending on when it was configured.
+const MEASUREMENT_TIMEOUT_SEQUENCES: u32 = 3;
+
+/// Number of configuration checks (one per second) a calibration may take before it is aborted.
+const CALIBRATION_TIMEOUT_CHECKS: u32 = 3;
+
+/// ID register contents of the AD7172-2. The lowest nibble is undefined.
+const AD7172_2_ID: u16 = 0x00D0;
+const ID_MASK: u16 = 0xFFF0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AdcError {
+    /// The checksum of a transaction did not match, even after retrying.
+    Checksum,
+    /// The ADC did not complete an operation in time.
+    Timeout,
+    /// The status of a conversion result indicates an error.
+    Conversion,
+}
+
 // ADC Register Adresses
 #[allow(unused)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum AdcReg {
+    STATUS = 0x00,
     ID = 0x7,
     ADCMODE = 0x1,
     IFMODE = 0x2,
@@ -54,6 +121,98 @@ pub enum AdcReg {
     GAIN3 = 0x3b,
 }
 
+/// Channel, offset and gain registers by ADC channel. Channel x uses setup x.
+const CH_REGS: [AdcReg; 4] = [AdcReg::CH0, AdcReg::CH1, AdcReg::CH2, AdcReg::CH3];
+const OFFSET_REGS: [AdcReg; 4] = [
+    AdcReg::OFFSET0,
+    AdcReg::OFFSET1,
+    AdcReg::OFFSET2,
+    AdcReg::OFFSET3,
+];
+const GAIN_REGS: [AdcReg; 4] = [AdcReg::GAIN0, AdcReg::GAIN1, AdcReg::GAIN2, AdcReg::GAIN3];
+
+impl AdcReg {
+    /// The size of the register in bytes.
+    fn size(&self) -> u8 {
+        match self {
+            AdcReg::STATUS => 1,
+            AdcReg::DATA => 3,
+            AdcReg::OFFSET0 | AdcReg::OFFSET1 | AdcReg::OFFSET2 | AdcReg::OFFSET3 => 3,
+            AdcReg::GAIN0 | AdcReg::GAIN1 | AdcReg::GAIN2 | AdcReg::GAIN3 => 3,
+            _ => 2,
+        }
+    }
+}
+
+/// ADC calibrations. The internal full-scale calibration of the AD7172-2 is done in the factory.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Calibration {
+    /// Internal zero-scale calibration. The inputs are shorted internally.
+    InternalZero,
+    /// System zero-scale calibration. The zero-scale input has to be applied to the channel.
+    SystemZero,
+    /// System full-scale calibration. The full-scale input has to be applied to the channel.
+    SystemFull,
+}
+
+impl Calibration {
+    /// The ADCMODE operating mode performing the calibration.
+    fn mode(&self) -> u32 {
+        match self {
+            Calibration::InternalZero => 0b100,
+            Calibration::SystemZero => 0b110,
+            Calibration::SystemFull => 0b111,
+        }
+    }
+}
+
+/// ADC acquisition modes.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Miniconf)]
+pub enum AdcMode {
+    /// The channels are converted continuously at the output data rate.
+    Continuous,
+    /// The ADC is in standby and converts each channel once per trigger.
+    Triggered,
+}
+
+/// ADC diagnostic measurements.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Diagnostic {
+    /// The internal temperature sensor against the internal reference.
+    Temperature,
+    /// The supply (AVDD1 - AVSS) / 5 against the internal reference.
+    Supply,
+    /// The external reference (REF+ - REF-) against the supply (AVDD1 - AVSS).
+    Reference,
+}
+
+impl Diagnostic {
+    /// The positive and negative analog inputs.
+    fn inputs(&self) -> (u32, u32) {
+        match self {
+            Diagnostic::Temperature => (0b10001, 0b10010),
+            Diagnostic::Supply => (0b10011, 0b10100),
+            Diagnostic::Reference => (0b10101, 0b10110),
+        }
+    }
+
+    /// The SETUPCON register contents.
+    fn setup(&self) -> u32 {
+        let buffers = Setupcon::AINBUFP as u32 | Setupcon::AINBUFN as u32;
+        match self {
+            // The reference buffers lack the headroom for the supply as reference.
+            Diagnostic::Reference => Setupcon::DIAREF as u32 | buffers,
+            _ => {
+                Setupcon::INTREF as u32
+                    | Setupcon::REFBUFP as u32
+                    | Setupcon::REFBUFN as u32
+                    | buffers
+            }
+        }
+    }
+}
+
 // ADC SETUPCON register settings.
 #[allow(unused)]
 enum Setupcon {
@@ -62,8 +221,43 @@ enum Setupcon {
     AINBUFP = 1 << 9,     // AINBUF+
     AINBUFN = 1 << 8,     // AINBUF-
     BIUNIPOLAR = 1 << 12, // BI_UNIPOLAR
-    INTREF = 10 << 4,     // Internal 2,5V reference
-    DIAREF = 11 << 4,     // diagnostic reference
+    INTREF = 0b10 << 4,   // Internal 2,5V reference
+    DIAREF = 0b11 << 4,   // diagnostic reference
+}
+
+/// Decoded ADC status register.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AdcStatus {
+    /// A new conversion result is available (RDY is active low).
+    pub ready: bool,
+    /// The conversion result was clamped due to an overrange or underrange, or another ADC error
+    /// occurred.
+    pub adc_error: bool,
+    /// A CRC error occurred on a register write.
+    pub crc_error: bool,
+    /// The register contents changed since the register integrity check was enabled.
+    pub reg_error: bool,
+    /// The channel the conversion result belongs to.
+    pub channel: u8,
+}
+
+impl AdcStatus {
+    /// Check if any of the error flags is set.
+    pub fn is_error(&self) -> bool {
+        self.adc_error || self.crc_error || self.reg_error
+    }
+}
+
+impl From<u8> for AdcStatus {
+    fn from(status: u8) -> Self {
+        Self {
+            ready: status & 1 << 7 == 0,
+            adc_error: status & 1 << 6 != 0,
+            crc_error: status & 1 << 5 != 0,
+            reg_error: status & 1 << 4 != 0,
+            channel: status & 0b11,
+        }
+    }
 }
 
 pub type AdcSpi = Spi<
@@ -85,6 +279,35 @@ pub struct AdcPins {
 pub struct Adc {
     spi: AdcSpi,
     sync: PB12<Output<PushPull>>,
+    /// Indicates that checksums are enabled in the IFMODE register.
+    crc: bool,
+    /// Number of transactions with a checksum mismatch.
+    crc_errors: u32,
+    /// The intended contents of the configuration registers in the order they were written.
+    config: Vec<(AdcReg, u32), 24>,
+    /// Number of times the ADC was reinitialized because its configuration diverged.
+    reinits: u32,
+    /// The power-on offset and gain coefficients of the channels.
+    factory: [AdcCalibration; 2],
+    /// The auxiliary channels to sample.
+    aux: [bool; 2],
+    /// Number of control sequences between auxiliary samples.
+    aux_interval: u32,
+    /// Number of control sequences since the last auxiliary samples.
+    sequences: u32,
+    /// The diagnostic to measure on the diagnostic channel.
+    diagnostic: Option<Diagnostic>,
+    /// Indicates that the diagnostic channel is configured for the diagnostic.
+    measuring: bool,
+    /// Number of conversion sequences since the diagnostic channel was configured.
+    measurement_sequences: u32,
+    /// The result of the last diagnostic measurement.
+    measurement: Option<Result<u32, AdcError>>,
+    /// The channel and the calibration in progress.
+    calibration: Option<(u8, Calibration)>,
+    /// Number of configuration checks since the calibration started.
+    calibration_checks: u32,
+    mode: AdcMode,
 }
 
 impl Adc {
@@ -100,20 +323,48 @@ impl Adc {
         let mut adc = Adc {
             spi,
             sync: pins.sync,
+            crc: false,
+            crc_errors: 0,
+            config: Vec::new(),
+            reinits: 0,
+            factory: [AdcCalibration::default(); 2],
+            aux: [false; 2],
+            aux_interval: 1,
+            sequences: 0,
+            diagnostic: None,
+            measuring: false,
+            measurement_sequences: 0,
+            measurement: None,
+            calibration: None,
+            calibration_checks: 0,
+            mode: AdcMode::Continuous,
         };
 
         adc.reset();
 
-        info!("ADC ID: {:#X}", adc.read_reg(AdcReg::ID, 2));
+        // Setup IFMODE register. Enable data stat to get channel info on conversions and CRC
+        // checksums to detect corrupted transactions.
+        adc.configure(AdcReg::IFMODE, IFMODE_DATA_STAT | IFMODE_CRC_EN);
 
-        // Setup ADCMODE register. Internal reference, internal clock, no delay, continuous conversion.
-        adc.write_reg(AdcReg::ADCMODE, 2, 0x8000);
+        match adc.id() {
+            Ok(id) if id & ID_MASK == AD7172_2_ID => info!("ADC ID: {:#X}", id),
+            Ok(id) => warn!("Unexpected ADC ID: {:#X}", id),
+            Err(_) => warn!("ADC ID unreadable"),
+        }
 
-        // Setup IFMODE register. Only enable data stat to get channel info on conversions.
-        adc.write_reg(AdcReg::IFMODE, 2, 0b100_0000);
+        // Setup ADCMODE register. Internal reference, internal clock, no delay, continuous conversion.
+        adc.configure(AdcReg::ADCMODE, 0x8000);
 
         adc.setup_channels();
 
+        for ch in 0..adc.factory.len() {
+            adc.factory[ch] = AdcCalibration {
+                offset: adc.read_reg(OFFSET_REGS[ch], 3).unwrap_or(0),
+                gain: adc.read_reg(GAIN_REGS[ch], 3).unwrap_or(0),
+            };
+        }
+        info!("ADC factory calibration: {:?}", adc.factory);
+
         adc
     }
 
@@ -132,73 +383,218 @@ impl Adc {
             }
         };
         cortex_m::asm::delay(20000); // minimum waiting time in clock cycles ADJUST FOR OTHER SYSTEMS
+        self.crc = false; // The reset disables checksums.
+    }
+
+    /// Read the ID register.
+    pub fn id(&mut self) -> Result<u16, AdcError> {
+        self.read_reg(AdcReg::ID, 2).map(|id| id as u16)
+    }
+
+    /// Check if the ADC responds with the ID of an AD7172-2.
+    pub fn is_present(&mut self) -> bool {
+        self.id().map_or(false, |id| id & ID_MASK == AD7172_2_ID)
+    }
+
+    /// Get the number of transactions with a checksum mismatch.
+    pub fn crc_errors(&self) -> u32 {
+        self.crc_errors
+    }
+
+    /// Get the number of times the ADC was reinitialized because its configuration diverged.
+    pub fn reinits(&self) -> u32 {
+        self.reinits
+    }
+
+    /// Write a configuration register and record its intended contents.
+    fn configure(&mut self, addr: AdcReg, data: u32) {
+        self.write_reg(addr, addr.size(), data);
+        if addr == AdcReg::IFMODE {
+            self.crc = data & IFMODE_CRC_EN != 0;
+        }
+
+        match self.config.iter_mut().find(|(reg, _)| *reg == addr) {
+            Some((_, value)) => *value = data,
+            // Note(unwrap): The number of configuration registers is bounded by the capacity.
+            None => self.config.push((addr, data)).unwrap(),
+        }
+    }
+
+    /// Get the intended contents of a configuration register.
+    fn intended(&self, addr: AdcReg) -> Option<u32> {
+        self.config
+            .iter()
+            .find(|(reg, _)| *reg == addr)
+            .map(|(_, data)| *data)
+    }
+
+    /// Compare the configuration registers with their intended contents.
+    ///
+    /// # Returns
+    /// True if all registers could be read back and match.
+    fn verify(&mut self) -> bool {
+        for i in 0..self.config.len() {
+            let (addr, expected) = self.config[i];
+            // The diagnostic channel temporarily diverges during diagnostic measurements.
+            if self.measuring && (addr == AdcReg::SETUPCON3 || addr == AdcReg::CH3) {
+                continue;
+            }
+            // The ADC changes the operating mode by itself, e.g. after single conversions.
+            let mask = match addr {
+                AdcReg::ADCMODE => !ADCMODE_MODE_MASK,
+                _ => !0,
+            };
+            match self.read_reg(addr, addr.size()) {
+                Ok(value) if value & mask == expected & mask => {}
+                Ok(value) => {
+                    warn!(
+                        "ADC register {:?} is {:#X}, expected {:#X}",
+                        addr, value, expected
+                    );
+                    return false;
+                }
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    /// Verify the configuration and reinitialize the ADC if it diverged, e.g. after a brown-out
+    /// reset of the ADC.
+    ///
+    /// # Returns
+    /// True if the ADC was reinitialized.
+    pub fn check_config(&mut self) -> bool {
+        // The channels and the operating mode intentionally diverge during a calibration.
+        if let Some((ch, calibration)) = self.calibration {
+            self.calibration_checks += 1;
+            if self.calibration_checks >= CALIBRATION_TIMEOUT_CHECKS {
+                warn!("ADC channel {} {:?} timed out", ch, calibration);
+                self.abort_calibration();
+            }
+            return false;
+        }
+
+        if self.verify() {
+            return false;
+        }
+
+        warn!("ADC configuration diverged, reinitializing");
+        self.reinits += 1;
+        self.reset();
+        // A diagnostic measurement in progress is lost.
+        if self.measuring {
+            self.measuring = false;
+            self.diagnostic = None;
+            self.measurement = Some(Err(AdcError::Timeout));
+        }
+        // The configuration is restored in the original order, so checksums are enabled first.
+        let config = self.config.clone();
+        for (addr, data) in config.iter() {
+            self.configure(*addr, *data);
+        }
+        true
     }
 
     /// Read a ADC register of size in bytes.
-    fn read_reg(&mut self, addr: AdcReg, size: u8) -> u32 {
-        let mut buf = [addr as u8 | 0x40, 0, 0, 0, 0];
+    /// Transactions with a checksum mismatch are retried.
+    fn read_reg(&mut self, addr: AdcReg, size: u8) -> Result<u32, AdcError> {
+        for _ in 0..=MAX_RETRIES {
+            match self.try_read_reg(addr, size) {
+                Ok(data) => return Ok(data),
+                Err(_) => self.crc_errors += 1,
+            }
+        }
+        warn!("ADC read of {:?} failed: checksum mismatch", addr);
+        Err(AdcError::Checksum)
+    }
+
+    /// Read a ADC register of size in bytes once.
+    fn try_read_reg(&mut self, addr: AdcReg, size: u8) -> Result<u32, AdcError> {
+        let mut buf = [addr as u8 | 0x40, 0, 0, 0, 0, 0];
+        // The checksum follows the data.
+        let len = (size + 1) as usize + self.crc as usize;
         self.sync.set_low().unwrap();
-        self.spi.transfer(&mut buf[..(size + 1) as usize]).unwrap();
+        self.spi.transfer(&mut buf[..len]).unwrap();
+        self.sync.set_high().unwrap();
+        // The checksum covers the command and the data. The data was overwritten by the transfer.
+        buf[0] = addr as u8 | 0x40;
+        if self.crc && crc8(&buf[..len]) != 0 {
+            return Err(AdcError::Checksum);
+        }
         let data = match size {
-            1 => buf[1].clone() as u32,
+            1 => buf[1] as u32,
             2 => BigEndian::read_u16(&buf[1..3]) as u32,
             3 => BigEndian::read_u24(&buf[1..4]) as u32,
             4 => BigEndian::read_u32(&buf[1..5]) as u32,
             _ => 0,
         };
-        self.sync.set_high().unwrap();
-        return data;
+        Ok(data)
     }
 
     /// Write a ADC register of size in bytes.
+    /// Writes the ADC reports a checksum mismatch for are retried.
     fn write_reg(&mut self, addr: AdcReg, size: u8, data: u32) {
-        let mut addr_buf = [addr as u8];
-        self.sync.set_low().unwrap();
-        self.spi.write(&mut addr_buf).unwrap();
-        let mut buf = [0, 0, 0, 0];
-        BigEndian::write_u32(&mut buf, data);
-        match size {
-            1 => self.spi.transfer(&mut buf[3..4]).unwrap(),
-            2 => self.spi.transfer(&mut buf[2..4]).unwrap(),
-            3 => self.spi.transfer(&mut buf[1..4]).unwrap(),
-            4 => self.spi.transfer(&mut buf[0..4]).unwrap(),
-            _ => &[0],
-        };
-        self.sync.set_high().unwrap();
+        for _ in 0..=MAX_RETRIES {
+            self.try_write_reg(addr, size, data);
+            if !self.crc {
+                return;
+            }
+            // The ADC flags a checksum mismatch in the status register.
+            match self.read_status() {
+                Ok(status) if !status.crc_error => return,
+                Ok(_) => self.crc_errors += 1,
+                Err(_) => {}
+            }
+        }
+        warn!("ADC write of {:?} failed: checksum mismatch", addr);
     }
 
-    /// Reads the status register and returns the value.
-    pub fn get_status_reg(&mut self) -> u8 {
-        let mut addr_buf = [0];
+    /// Write a ADC register of size in bytes once.
+    fn try_write_reg(&mut self, addr: AdcReg, size: u8, data: u32) {
+        let mut buf = [0u8; 6];
+        buf[0] = addr as u8;
+        let mut word = [0u8; 4];
+        BigEndian::write_u32(&mut word, data);
+        let size = size.min(4) as usize;
+        buf[1..=size].copy_from_slice(&word[4 - size..]);
+        let mut len = size + 1;
+        if self.crc {
+            buf[len] = crc8(&buf[..len]);
+            len += 1;
+        }
         self.sync.set_low().unwrap();
-        self.spi.transfer(&mut addr_buf).unwrap();
+        self.spi.write(&buf[..len]).unwrap();
         self.sync.set_high().unwrap();
-        addr_buf[0]
     }
 
-    /// Reads the data register and returns data and channel information.
+    /// Reads and decodes the status register.
+    pub fn read_status(&mut self) -> Result<AdcStatus, AdcError> {
+        Ok(AdcStatus::from(self.read_reg(AdcReg::STATUS, 1)? as u8))
+    }
+
+    /// Reads the data register and returns data and the status of the conversion.
     /// The DATA_STAT bit has to be set in the IFMODE register.
-    pub fn read_data(&mut self) -> (u32, u8) {
-        let datach = self.read_reg(AdcReg::DATA, 4);
-        let ch = (datach & 0x3) as u8;
-        let data = datach >> 8;
-        (data, ch)
+    pub fn read_data(&mut self) -> Result<(u32, AdcStatus), AdcError> {
+        let datastat = self.read_reg(AdcReg::DATA, 4)?;
+        let status = AdcStatus::from(datastat as u8);
+        let data = datastat >> 8;
+        Ok((data, status))
     }
 
     /// Setup ADC channels.
     fn setup_channels(&mut self) {
         // enable first channel and configure Ain0, Ain1,
         // set config 0 for second channel,
-        self.write_reg(AdcReg::CH0, 2, 0x8001);
+        self.configure(AdcReg::CH0, 0x8001);
 
         // enable second channel and configure Ain2, Ain3,
         // set config 1 for second channel,
-        self.write_reg(AdcReg::CH1, 2, 0x9043);
+        self.configure(AdcReg::CH1, 0x9043);
 
         // Setup configuration register ch0
-        self.write_reg(
+        self.configure(
             AdcReg::SETUPCON0,
-            2,
             Setupcon::REFBUFP as u32
                 | Setupcon::REFBUFN as u32
                 | Setupcon::AINBUFP as u32
@@ -208,9 +604,8 @@ impl Adc {
         );
 
         // Setup configuration register ch1
-        self.write_reg(
+        self.configure(
             AdcReg::SETUPCON1,
-            2,
             Setupcon::REFBUFP as u32
                 | Setupcon::REFBUFN as u32
                 | Setupcon::AINBUFP as u32
@@ -220,16 +615,406 @@ impl Adc {
         );
 
         // Setup filter register ch0. 10Hz data rate. Sinc5Sinc1 Filter.
-        self.write_reg(AdcReg::FILTCON0, 2, 0b110 << 8 | 0b10110);
+        self.configure(AdcReg::FILTCON0, 0b110 << 8 | 0b10110);
 
         // Setup filter register ch1. 10Hz data rate. Sinc5Sinc1 Filter.
-        self.write_reg(AdcReg::FILTCON1, 2, 0b110 << 8 | 0b10110);
+        self.configure(AdcReg::FILTCON1, 0b110 << 8 | 0b10110);
+    }
+
+    /// Set the offset and gain coefficients of an ADC channel.
+    ///
+    /// # Args
+    /// * `ch` - The ADC channel.
+    /// * `calibration` - The coefficients. Zero coefficients are replaced by the power-on ones.
+    pub fn set_calibration(&mut self, ch: u8, calibration: AdcCalibration) {
+        self.interrupt_calibration();
+        let ch = ch as usize;
+        let offset = match calibration.offset {
+            0 => self.factory[ch].offset,
+            offset => offset,
+        };
+        let gain = match calibration.gain {
+            0 => self.factory[ch].gain,
+            gain => gain,
+        };
+        self.configure(OFFSET_REGS[ch], offset);
+        self.configure(GAIN_REGS[ch], gain);
+    }
+
+    /// Start a calibration of an ADC channel, which replaces a calibration in progress.
+    ///
+    /// # Note
+    /// The calibration takes about one conversion period, during which the other channels are
+    /// disabled. The ADC reports ready once it completed, then it is finished with
+    /// `finish_calibration`. Calibrations that do not complete are aborted by `check_config`.
+    ///
+    /// # Args
+    /// * `ch` - The ADC channel.
+    /// * `calibration` - The calibration to run.
+    pub fn start_calibration(&mut self, ch: u8, calibration: Calibration) {
+        self.postpone_measurement();
+        // The ADC calibrates the enabled channel.
+        self.suspend_channels(Some(ch as usize));
+        self.override_mode(calibration.mode());
+        self.calibration = Some((ch, calibration));
+        self.calibration_checks = 0;
+    }
+
+    /// Check if a calibration is in progress. The ADC reporting ready indicates its completion
+    /// instead of a conversion result.
+    pub fn is_calibrating(&self) -> bool {
+        self.calibration.is_some()
+    }
+
+    /// Read the result of a completed calibration, apply it and resume the conversions.
+    ///
+    /// # Returns
+    /// The ADC channel and its resulting offset and gain coefficients. `None` if no calibration
+    /// was in progress or the coefficients could not be read.
+    pub fn finish_calibration(&mut self) -> Option<(u8, AdcCalibration)> {
+        let (ch, calibration) = self.calibration?;
+        let offset = self.read_reg(OFFSET_REGS[ch as usize], 3);
+        let gain = self.read_reg(GAIN_REGS[ch as usize], 3);
+        let result = offset.and_then(|offset| gain.map(|gain| AdcCalibration { offset, gain }));
+        self.abort_calibration();
+
+        match result {
+            Ok(coefficients) => {
+                info!("ADC channel {} {:?}: {:?}", ch, calibration, coefficients);
+                self.set_calibration(ch, coefficients);
+                Some((ch, coefficients))
+            }
+            Err(error) => {
+                warn!("ADC channel {} {:?} failed: {:?}", ch, calibration, error);
+                None
+            }
+        }
+    }
+
+    /// Abort a calibration in progress as the configuration changes. Its result could be
+    /// overwritten.
+    fn interrupt_calibration(&mut self) {
+        if let Some((ch, calibration)) = self.calibration {
+            warn!("ADC channel {} {:?} interrupted", ch, calibration);
+            self.abort_calibration();
+        }
+    }
+
+    /// End a calibration in progress without its result and resume the conversions.
+    fn abort_calibration(&mut self) {
+        if self.calibration.take().is_some() {
+            // The ADC is in standby after the calibration.
+            self.restore(&CH_REGS);
+            self.restore(&[AdcReg::ADCMODE]);
+        }
+    }
+
+    /// Select the acquisition mode.
+    pub fn set_acquisition(&mut self, mode: AdcMode) {
+        self.interrupt_calibration();
+        let operating_mode = match mode {
+            AdcMode::Continuous => MODE_CONTINUOUS,
+            AdcMode::Triggered => MODE_STANDBY,
+        };
+        let adcmode = self.intended(AdcReg::ADCMODE).unwrap_or(0);
+        self.configure(
+            AdcReg::ADCMODE,
+            adcmode & !ADCMODE_MODE_MASK | operating_mode << ADCMODE_MODE_SHIFT,
+        );
+        self.mode = mode;
+    }
+
+    /// Trigger a conversion of each enabled channel in triggered acquisition mode. The ADC
+    /// returns to standby afterwards.
+    ///
+    /// # Note
+    /// A conversion sequence in progress is restarted, so the trigger rate must be below the
+    /// sequence rate.
+    pub fn trigger(&mut self) {
+        if self.mode == AdcMode::Triggered && self.calibration.is_none() {
+            self.override_mode(MODE_SINGLE);
+        }
+    }
+
+    /// Start a diagnostic measurement, which replaces any pending one.
+    ///
+    /// # Note
+    /// Like the auxiliary channels, the diagnostic is converted on the diagnostic channel
+    /// interleaved with the control channels. The channel is configured for it once the current
+    /// conversion sequence completed. The result is obtained with `take_measurement`.
+    pub fn start_measurement(&mut self, diagnostic: Diagnostic) {
+        if self.measuring {
+            self.end_measurement(None);
+        }
+        self.measurement = None;
+        self.diagnostic = Some(diagnostic);
+    }
+
+    /// Take the result of the last diagnostic measurement.
+    ///
+    /// # Returns
+    /// The raw conversion result once the measurement completed, `None` while it is pending.
+    pub fn take_measurement(&mut self) -> Option<Result<u32, AdcError>> {
+        self.measurement.take()
+    }
+
+    /// Check if a sample of the diagnostic channel is the result of a diagnostic measurement.
+    pub fn is_measuring(&self) -> bool {
+        self.measuring
+    }
+
+    /// Account for the sample of a diagnostic measurement.
+    ///
+    /// # Args
+    /// * `data` - The conversion result.
+    /// * `status` - The status of the conversion.
+    pub fn measured(&mut self, data: u32, status: AdcStatus) {
+        if !self.measuring || status.channel != DIAGNOSTIC_CHANNEL {
+            return;
+        }
+        let result = if status.is_error() {
+            Err(AdcError::Conversion)
+        } else {
+            Ok(data)
+        };
+        self.end_measurement(Some(result));
+    }
+
+    /// Configure the diagnostic channel for the pending diagnostic.
+    fn begin_measurement(&mut self) {
+        if let Some(diagnostic) = self.diagnostic {
+            let (ainpos, ainneg) = diagnostic.inputs();
+            self.write_reg(AdcReg::SETUPCON3, 2, diagnostic.setup());
+            self.write_reg(
+                AdcReg::CH3,
+                2,
+                CH_EN
+                    | (DIAGNOSTIC_CHANNEL as u32) << CH_SETUP_SHIFT
+                    | ainpos << CH_AINPOS_SHIFT
+                    | ainneg,
+            );
+            self.measuring = true;
+            self.measurement_sequences = 0;
+        }
+    }
+
+    /// Restore the diagnostic channel and repeat a measurement in progress after the next
+    /// conversion sequence, e.g. as the channel is reconfigured.
+    fn postpone_measurement(&mut self) {
+        if self.measuring {
+            self.restore(&[AdcReg::SETUPCON3, AdcReg::CH3]);
+            self.measuring = false;
+        }
+    }
+
+    /// Restore the diagnostic channel and record the result of the measurement.
+    fn end_measurement(&mut self, result: Option<Result<u32, AdcError>>) {
+        self.restore(&[AdcReg::SETUPCON3, AdcReg::CH3]);
+        self.measuring = false;
+        if let (Some(diagnostic), Some(Err(error))) = (self.diagnostic, result) {
+            warn!("ADC {:?} measurement failed: {:?}", diagnostic, error);
+        }
+        self.diagnostic = None;
+        self.measurement = result;
+    }
+
+    /// Disable the conversions of all channels but one.
+    fn suspend_channels(&mut self, except: Option<usize>) {
+        for (i, reg) in CH_REGS.iter().enumerate() {
+            if let Some(data) = self.intended(*reg) {
+                if Some(i) != except {
+                    self.write_reg(*reg, 2, data & !CH_EN);
+                }
+            }
+        }
+    }
+
+    /// Temporarily change the ADCMODE operating mode.
+    fn override_mode(&mut self, mode: u32) {
+        let adcmode = self.intended(AdcReg::ADCMODE).unwrap_or(0);
+        self.write_reg(
+            AdcReg::ADCMODE,
+            2,
+            adcmode & !ADCMODE_MODE_MASK | mode << ADCMODE_MODE_SHIFT,
+        );
+    }
+
+    /// Restore configuration registers to their intended contents.
+    fn restore(&mut self, regs: &[AdcReg]) {
+        for reg in regs.iter() {
+            if let Some(data) = self.intended(*reg) {
+                self.write_reg(*reg, reg.size(), data);
+            }
+        }
     }
 
     /// Set both ADC channel filter config to the same settings.
     pub fn set_filters(&mut self, set: AdcFilterSettings) {
+        self.interrupt_calibration();
         let reg: u32 = (set.odr | set.order << 5 | set.enhfilt << 8 | set.enhfilten << 11) as u32;
-        self.write_reg(AdcReg::FILTCON0, 2, reg);
-        self.write_reg(AdcReg::FILTCON1, 2, reg);
+        self.configure(AdcReg::FILTCON0, reg);
+        self.configure(AdcReg::FILTCON1, reg);
+        self.configure(AdcReg::FILTCON2, reg);
+        self.configure(AdcReg::FILTCON3, reg);
+    }
+
+    /// Configure the auxiliary channels CH2 and CH3.
+    ///
+    /// # Note
+    /// The auxiliary channels are measured single-ended against the internal reference. They are
+    /// only enabled for one conversion sequence every `interval` sequences of the control
+    /// channels, so they barely affect the control rate.
+    ///
+    /// # Args
+    /// * `aux` - The settings of the auxiliary channels.
+    /// * `interval` - The number of control sequences between auxiliary samples.
+    pub fn set_aux(&mut self, aux: &[AuxChannelSettings; 2], interval: u32) {
+        self.interrupt_calibration();
+        self.postpone_measurement();
+        for (i, settings) in aux.iter().enumerate() {
+            let ch = AUX_CHANNEL as usize + i;
+            self.configure(
+                [AdcReg::SETUPCON2, AdcReg::SETUPCON3][i],
+                Setupcon::INTREF as u32
+                    | Setupcon::REFBUFP as u32
+                    | Setupcon::REFBUFN as u32
+                    | Setupcon::AINBUFP as u32
+                    | Setupcon::AINBUFN as u32,
+            );
+            // The channels are enabled when they are due.
+            self.configure(
+                CH_REGS[ch],
+                (ch as u32) << CH_SETUP_SHIFT
+                    | (settings.ainpos & CH_AIN_MASK) << CH_AINPOS_SHIFT
+                    | settings.ainneg & CH_AIN_MASK,
+            );
+            self.aux[i] = settings.enable;
+        }
+        self.aux_interval = interval.max(1);
+        self.sequences = 0;
+    }
+
+    /// Enable or disable the auxiliary channels, which are to be sampled.
+    ///
+    /// # Note
+    /// The diagnostic channel is left untouched during a diagnostic measurement.
+    fn enable_aux(&mut self, enable: bool) {
+        for i in 0..self.aux.len() {
+            let ch = AUX_CHANNEL + i as u8;
+            if self.measuring && ch == DIAGNOSTIC_CHANNEL {
+                continue;
+            }
+            let reg = CH_REGS[ch as usize];
+            if let Some(data) = self.intended(reg) {
+                let data = if enable && self.aux[i] {
+                    data | CH_EN
+                } else {
+                    data & !CH_EN
+                };
+                self.configure(reg, data);
+            }
+        }
+    }
+
+    /// Account for a completed conversion sequence of the control channels. Configures the
+    /// diagnostic channel for a pending diagnostic, abandons a diagnostic measurement without
+    /// result and enables the auxiliary channels for the next sequence when they are due.
+    pub fn sequence_completed(&mut self) {
+        if self.measuring {
+            self.measurement_sequences += 1;
+            if self.measurement_sequences >= MEASUREMENT_TIMEOUT_SEQUENCES {
+                self.end_measurement(Some(Err(AdcError::Timeout)));
+            }
+        } else if self.diagnostic.is_some() {
+            self.begin_measurement();
+        }
+        if !self.aux.iter().any(|&enabled| enabled) {
+            return;
+        }
+        self.sequences += 1;
+        if self.sequences >= self.aux_interval {
+            self.sequences = 0;
+            self.enable_aux(true);
+        }
+    }
+
+    /// Account for a sample of an auxiliary channel and disable the auxiliary channels after the
+    /// last one was sampled.
+    ///
+    /// # Args
+    /// * `ch` - The ADC channel of the sample.
+    pub fn aux_sampled(&mut self, ch: u8) {
+        let measuring = self.measuring;
+        let last = self.aux.iter().enumerate().rposition(|(i, &enabled)| {
+            enabled && !(measuring && i as u8 + AUX_CHANNEL == DIAGNOSTIC_CHANNEL)
+        });
+        if last.map(|i| i as u8 + AUX_CHANNEL) == Some(ch) {
+            self.enable_aux(false);
+        }
+    }
+}
+
+/// Get the time to convert a channel when converting a sequence of channels.
+///
+/// # Args
+/// * `filter` - The filter settings of the channels.
+///
+/// # Returns
+/// The conversion time in s or `None` if the filter settings are reserved ones.
+pub fn conversion_time(filter: &AdcFilterSettings) -> Option<f32> {
+    let odr = ODR.get(filter.odr as usize)?;
+    let settling = match (filter.order, filter.enhfilten) {
+        // Sinc5+Sinc1 filter
+        (0, 0) => 1.0 / odr,
+        // Sinc5+Sinc1 filter with a postfilter for simultaneous 50 Hz and 60 Hz rejection
+        (0, 1) => match filter.enhfilt {
+            0b010 => 36.7e-3,
+            0b011 => 40e-3,
+            0b101 => 50e-3,
+            0b110 => 60e-3,
+            _ => return None,
+        },
+        // Sinc3 filter
+        (0b11, _) => 3.0 / odr,
+        _ => return None,
+    };
+    Some(settling + SETTLING_MARGIN)
+}
+
+/// CRC-8 with the polynomial x^8 + x^2 + x + 1 used by the ADC.
+///
+/// # Note
+/// Including a correct checksum in the input yields zero.
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc8_check_value() {
+        // The polynomial without reflection or final XOR is CRC-8/SMBUS.
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(&[]), 0x00);
+    }
+
+    #[test]
+    fn crc8_of_checked_data_is_zero() {
+        // A read of the ID register: command byte, ID and checksum.
+        let mut transaction = [0x40 | AdcReg::ID as u8, 0x00, 0xD0, 0x00];
+        transaction[3] = crc8(&transaction[..3]);
+        assert_eq!(crc8(&transaction), 0);
+        transaction[2] ^= 0x01;
+        assert_ne!(crc8(&transaction), 0);
     }
 }
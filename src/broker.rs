@@ -0,0 +1,322 @@
+///! MQTT broker address management
+///!
+///! # Design
+///! A primary and an optional secondary broker are configured, each either as an IPv4 address or
+///! as a host name that is resolved via DNS. The MQTT clients are bound to a fixed broker address
+///! on construction. To allow the address to change at run-time (e.g. after a host name was
+///! re-resolved or on failover), the clients use a `BrokerStack`, which substitutes the currently
+///! active broker address whenever a client connects.
+///!
+///! Connection attempts are limited in time. After a failed attempt, connecting is deferred with
+///! an exponential backoff, host names are resolved again and, after repeated failures, the other
+///! broker is tried.
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use minimq::embedded_nal::{nb, IpAddr, Ipv4Addr, SocketAddr, TcpClientStack};
+
+use crate::dns::Resolver;
+use crate::fixed_string::FixedString;
+use crate::network_users::NetworkReference;
+use crate::system_timer::SystemTimer;
+
+/// Time within which a connection attempt must succeed.
+const ATTEMPT_TIMEOUT_MS: u64 = 5_000;
+
+/// Bounds of the delay between failed connection attempts.
+const MIN_BACKOFF_MS: u64 = 1_000;
+const MAX_BACKOFF_MS: u64 = 60_000;
+
+/// Number of consecutive failed attempts after which the other broker is tried.
+const FAILOVER_ATTEMPTS: u32 = 3;
+
+/// The active broker address. Zero while unknown.
+static BROKER_ADDRESS: AtomicU32 = AtomicU32::new(0);
+
+/// Indicates that the clients may attempt to connect.
+static CONNECT: AtomicBool = AtomicBool::new(false);
+
+/// Get the active broker address.
+pub fn address() -> Option<Ipv4Addr> {
+    match BROKER_ADDRESS.load(Ordering::Relaxed) {
+        0 => None,
+        address => Some(Ipv4Addr::from(address)),
+    }
+}
+
+fn set_address(address: Ipv4Addr) {
+    BROKER_ADDRESS.store(u32::from(address), Ordering::Relaxed);
+}
+
+/// A network stack proxy that connects TCP sockets to the active broker while connection attempts
+/// are permitted.
+pub struct BrokerStack {
+    stack: NetworkReference,
+}
+
+impl BrokerStack {
+    pub fn new(stack: NetworkReference) -> Self {
+        Self { stack }
+    }
+}
+
+impl TcpClientStack for BrokerStack {
+    type TcpSocket = <NetworkReference as TcpClientStack>::TcpSocket;
+    type Error = <NetworkReference as TcpClientStack>::Error;
+
+    fn socket(&mut self) -> Result<Self::TcpSocket, Self::Error> {
+        TcpClientStack::socket(&mut self.stack)
+    }
+
+    fn connect(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+        remote: SocketAddr,
+    ) -> nb::Result<(), Self::Error> {
+        match address().filter(|_| CONNECT.load(Ordering::Relaxed)) {
+            Some(address) => TcpClientStack::connect(
+                &mut self.stack,
+                socket,
+                SocketAddr::new(IpAddr::V4(address), remote.port()),
+            ),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    fn is_connected(&mut self, socket: &Self::TcpSocket) -> Result<bool, Self::Error> {
+        TcpClientStack::is_connected(&mut self.stack, socket)
+    }
+
+    fn send(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+        buffer: &[u8],
+    ) -> nb::Result<usize, Self::Error> {
+        TcpClientStack::send(&mut self.stack, socket, buffer)
+    }
+
+    fn receive(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<usize, Self::Error> {
+        TcpClientStack::receive(&mut self.stack, socket, buffer)
+    }
+
+    fn close(&mut self, socket: Self::TcpSocket) -> Result<(), Self::Error> {
+        TcpClientStack::close(&mut self.stack, socket)
+    }
+}
+
+/// Resolves the configured broker hosts and maintains the active broker address.
+pub struct Broker {
+    hosts: [FixedString<64>; 2],
+    /// Index of the active host.
+    active: usize,
+    /// `None` to use the router of the default route.
+    dns_server: Option<Ipv4Addr>,
+    resolver: Resolver,
+    /// Uptime at which the active host is resolved. `None` if no resolution is required.
+    resolve_at: Option<u64>,
+    connected: bool,
+    /// Indicates that a connection attempt is in progress.
+    attempting: bool,
+    /// End of the current connection attempt or earliest start of the next one.
+    deadline: u64,
+    backoff: u64,
+    failures: u32,
+    consecutive_failures: u32,
+}
+
+impl Broker {
+    /// Construct the broker manager.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the (shared) underlying network stack.
+    /// * `primary` - The primary broker IPv4 address or host name.
+    /// * `secondary` - The secondary broker IPv4 address or host name. Empty to disable failover.
+    /// * `dns_server` - The DNS server to resolve host names with. `None` to use the router of the
+    ///   default route, e.g. the one of the DHCP lease.
+    pub fn new(
+        stack: NetworkReference,
+        primary: FixedString<64>,
+        secondary: FixedString<64>,
+        dns_server: Option<Ipv4Addr>,
+    ) -> Self {
+        let mut broker = Self {
+            hosts: [primary, secondary],
+            active: 0,
+            dns_server,
+            resolver: Resolver::new(stack),
+            resolve_at: None,
+            connected: false,
+            attempting: false,
+            deadline: 0,
+            backoff: MIN_BACKOFF_MS,
+            failures: 0,
+            consecutive_failures: 0,
+        };
+        broker.activate(0, 0);
+        broker
+    }
+
+    /// Replace the configured brokers and make the primary one active.
+    ///
+    /// # Args
+    /// * `primary` - The primary broker IPv4 address or host name.
+    /// * `secondary` - The secondary broker IPv4 address or host name. Empty to disable failover.
+    pub fn set_hosts(&mut self, primary: FixedString<64>, secondary: FixedString<64>) {
+        self.hosts = [primary, secondary];
+        self.attempting = false;
+        self.backoff = MIN_BACKOFF_MS;
+        self.consecutive_failures = 0;
+        CONNECT.store(false, Ordering::Relaxed);
+        let now = SystemTimer::millis();
+        self.deadline = now;
+        self.activate(0, now);
+    }
+
+    /// Get the number of failed connection attempts.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Get the index of the active broker. 0 is the primary, 1 the secondary broker.
+    pub fn active(&self) -> u8 {
+        self.active as u8
+    }
+
+    /// Update the broker address and connection attempts.
+    ///
+    /// # Args
+    /// * `connected` - Indicates that the MQTT clients are connected to the broker.
+    pub fn update(&mut self, connected: bool) {
+        let now = SystemTimer::millis();
+
+        if connected {
+            if !self.connected {
+                log::info!("Connected to broker {}", self.hosts[self.active]);
+                self.connected = true;
+                self.attempting = false;
+                self.backoff = MIN_BACKOFF_MS;
+                self.consecutive_failures = 0;
+                self.resolver.abort();
+                self.resolve_at = None;
+            }
+            return;
+        }
+
+        if self.connected {
+            log::warn!("Lost connection to broker {}", self.hosts[self.active]);
+            self.connected = false;
+            self.deadline = now;
+        }
+
+        self.resolve(now);
+
+        if self.attempting {
+            if now >= self.deadline {
+                self.fail(now);
+            }
+        } else if now >= self.deadline && address().is_some() {
+            self.attempting = true;
+            self.deadline = now + ATTEMPT_TIMEOUT_MS;
+            CONNECT.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Resolve the active host name when due.
+    fn resolve(&mut self, now: u64) {
+        let resolve_at = match self.resolve_at {
+            Some(resolve_at) => resolve_at,
+            None => return,
+        };
+
+        if let Some(result) = self.resolver.poll() {
+            match result {
+                Ok(address) => {
+                    log::info!("Resolved broker {} to {}", self.hosts[self.active], address);
+                    set_address(address);
+                    self.resolve_at = None;
+                }
+                Err(error) => {
+                    log::warn!(
+                        "Resolving broker {} failed: {:?}",
+                        self.hosts[self.active],
+                        error
+                    );
+                    self.fail_resolution(now);
+                }
+            }
+        } else if !self.resolver.is_pending() && now >= resolve_at {
+            let host = self.hosts[self.active];
+            if let Err(error) = self.resolver.query(self.dns_server, host.as_str()) {
+                log::warn!("Resolving broker {} failed: {:?}", host, error);
+                self.fail_resolution(now);
+            }
+        }
+    }
+
+    /// Handle a failed resolution. The resolution is retried after the backoff.
+    fn fail_resolution(&mut self, now: u64) {
+        self.fail(now);
+        if self.is_host_name() {
+            self.resolve_at = Some(self.deadline);
+        }
+    }
+
+    /// Handle a failed connection attempt. Host names are resolved again during the backoff.
+    fn fail(&mut self, now: u64) {
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        self.attempting = false;
+        CONNECT.store(false, Ordering::Relaxed);
+
+        log::warn!(
+            "Connecting to broker {} failed, retrying in {} ms",
+            self.hosts[self.active],
+            self.backoff
+        );
+        self.deadline = now + self.backoff;
+
+        if self.consecutive_failures >= FAILOVER_ATTEMPTS && !self.hosts[1 - self.active].is_empty()
+        {
+            self.consecutive_failures = 0;
+            self.backoff = MIN_BACKOFF_MS;
+            self.activate(1 - self.active, self.deadline);
+            log::warn!("Failing over to broker {}", self.hosts[self.active]);
+        } else {
+            self.backoff = (2 * self.backoff).min(MAX_BACKOFF_MS);
+            // The address of a host name may have changed.
+            if self.is_host_name() {
+                self.resolve_at = Some(now);
+            }
+        }
+    }
+
+    /// Make a broker active.
+    ///
+    /// # Args
+    /// * `index` - The index of the broker.
+    /// * `resolve_at` - Uptime at which a host name is resolved.
+    fn activate(&mut self, index: usize, resolve_at: u64) {
+        self.active = index;
+        self.resolver.abort();
+        match self.hosts[index].as_str().parse::<Ipv4Addr>() {
+            Ok(address) => {
+                set_address(address);
+                self.resolve_at = None;
+            }
+            Err(_) => {
+                set_address(Ipv4Addr::UNSPECIFIED);
+                self.resolve_at = Some(resolve_at);
+            }
+        }
+    }
+
+    fn is_host_name(&self) -> bool {
+        self.hosts[self.active]
+            .as_str()
+            .parse::<Ipv4Addr>()
+            .is_err()
+    }
+}
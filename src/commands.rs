@@ -0,0 +1,85 @@
+///! Thermostat device commands
+///!
+///! # Design
+///! Commands are one-shot actions, as opposed to settings which describe persistent state. They
+///! are received on `<prefix>/command/<name>` with a command specific payload.
+use serde::Deserialize;
+
+use crate::adc::Calibration;
+
+/// A command received over the network.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Store the active settings in flash. Ignores the payload.
+    ///
+    /// The save completes in the background within about a second. Each step delays the other
+    /// tasks by up to a millisecond.
+    Save,
+    /// Reset the device once the response was sent and a settings save completed. Ignores the
+    /// payload.
+    Reset,
+    /// Run an ADC calibration of a channel and store the resulting coefficients. Other unsaved
+    /// settings are not stored. The payload is a JSON object, e.g.
+    /// `{"channel": 0, "calibration": "internal_zero"}`.
+    Calibrate(CalibrateArgs),
+    /// Submit the temperature of a reference thermometer and update the temperature correction
+    /// of a channel. The payload is a JSON object, e.g. `{"channel": 0, "temperature": 25.1}`.
+    AddReference(ReferenceArgs),
+    /// Remove the reference points of a channel and reset its temperature correction. The payload
+    /// is a JSON object, e.g. `{"channel": 0}`.
+    ClearReferences(ChannelArgs),
+}
+
+/// The payload of the calibrate command.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub struct CalibrateArgs {
+    /// The thermostat channel.
+    pub channel: u8,
+    pub calibration: Calibration,
+}
+
+/// The payload of the add reference command.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub struct ReferenceArgs {
+    /// The thermostat channel.
+    pub channel: u8,
+    /// The temperature of the reference thermometer in °C.
+    pub temperature: f32,
+}
+
+/// The payload of commands acting on a channel.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub struct ChannelArgs {
+    /// The thermostat channel.
+    pub channel: u8,
+}
+
+impl Command {
+    /// Parse a command.
+    ///
+    /// # Args
+    /// * `name` - The name of the command, i.e. the topic relative to `<prefix>/command`.
+    /// * `payload` - The command payload.
+    ///
+    /// # Returns
+    /// The command, if it is known and the payload is valid.
+    pub fn parse(name: &str, payload: &[u8]) -> Option<Self> {
+        match name {
+            "save" => Some(Command::Save),
+            "reset" => Some(Command::Reset),
+            "calibrate" => match serde_json_core::from_slice::<CalibrateArgs>(payload) {
+                Ok((args, _)) if args.channel < 2 => Some(Command::Calibrate(args)),
+                _ => None,
+            },
+            "add_reference" => match serde_json_core::from_slice::<ReferenceArgs>(payload) {
+                Ok((args, _)) if args.channel < 2 => Some(Command::AddReference(args)),
+                _ => None,
+            },
+            "clear_references" => match serde_json_core::from_slice::<ChannelArgs>(payload) {
+                Ok((args, _)) if args.channel < 2 => Some(Command::ClearReferences(args)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
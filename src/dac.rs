@@ -137,6 +137,28 @@ impl Pwms {
         self.set(i_to_pwm(max_i1), 4);
         self.set(i_to_pwm(min_i1), 5);
     }
+
+    /// Check that the PWM timers are running.
+    ///
+    /// # Returns
+    /// True for TIM3 (voltage limits) and TIM1 (current limits) if the timer is enabled and its
+    /// counter advances.
+    pub fn self_test(&self) -> [bool; 2] {
+        // Safety: Only status and counter registers are read.
+        let tim3 = unsafe { &*TIM3::ptr() };
+        let tim1 = unsafe { &*TIM1::ptr() };
+
+        let cnt3 = tim3.cnt.read().bits();
+        let cnt1 = tim1.cnt.read().bits();
+        delay(100); // Far below the PWM period, so the counters do not wrap around.
+        let tim3_running = tim3.cr1.read().cen().bit_is_set() && tim3.cnt.read().bits() != cnt3;
+        // The advanced timer outputs additionally require the main output enable.
+        let tim1_running = tim1.cr1.read().cen().bit_is_set()
+            && tim1.bdtr.read().moe().bit_is_set()
+            && tim1.cnt.read().bits() != cnt1;
+
+        [tim3_running, tim1_running]
+    }
 }
 
 /// DAC: https://www.analog.com/media/en/technical-documentation/data-sheets/AD5680.pdf
@@ -149,6 +171,10 @@ pub struct Dacs {
     sync1: PF6<Output<PushPull>>,
     shdn0: PE10<Output<PushPull>>,
     shdn1: PE15<Output<PushPull>>,
+    /// Keeps both TEC channels shut down.
+    inhibited: bool,
+    /// The channels that are not shut down.
+    enabled: [bool; 2],
 }
 
 impl Dacs {
@@ -176,6 +202,8 @@ impl Dacs {
             sync1: pins1.sync,
             shdn0: pins0.shdn,
             shdn1: pins1.shdn,
+            inhibited: false,
+            enabled: [false; 2],
         };
         dacs.dis_ch(0);
         dacs.dis_ch(1);
@@ -191,6 +219,11 @@ impl Dacs {
 
     /// Set the DAC output to value on a channel.
     pub fn set(&mut self, value: u32, ch: u8) {
+        self.write(value, ch).unwrap();
+    }
+
+    /// Write a value to the DAC of a channel.
+    fn write(&mut self, value: u32, ch: u8) -> Result<(), spi::Error> {
         let value = value.min(MAX_VALUE);
         // 24 bit transfer. First 6 bit and last 2 bit are low.
         let mut buf = [(value >> 14) as u8, (value >> 6) as u8, (value << 2) as u8];
@@ -199,25 +232,60 @@ impl Dacs {
             // must be high for >= 33 ns
             delay(100); // 100 * 5.95ns
             self.sync0.set_low().unwrap();
-            self.spi0.transfer(&mut buf).unwrap();
+            self.spi0.transfer(&mut buf)?;
             self.val[0] = value;
         } else {
             self.sync1.set_high().unwrap();
             // must be high for >= 33 ns
             delay(100); // 100 * 5.95ns
             self.sync1.set_low().unwrap();
-            self.spi1.transfer(&mut buf).unwrap();
+            self.spi1.transfer(&mut buf)?;
             self.val[1] = value;
         }
+        Ok(())
+    }
+
+    /// SPI smoke test: check that the SPI peripheral of each channel completes a transfer of its
+    /// current value.
+    ///
+    /// # Note
+    /// This is not a DAC presence check. The DACs can not be read back, so a missing or faulty DAC
+    /// passes as long as the SPI peripheral completes the transfer.
+    ///
+    /// # Returns
+    /// True for each channel that passed.
+    pub fn self_test(&mut self) -> [bool; 2] {
+        let mut passed = [false; 2];
+        for (ch, passed) in passed.iter_mut().enumerate() {
+            *passed = self.write(self.val[ch], ch as u8).is_ok();
+        }
+        passed
+    }
+
+    /// Keep both TEC channels shut down, e.g. after a failed self-test. Channels can not be
+    /// enabled afterwards.
+    pub fn inhibit(&mut self) {
+        self.inhibited = true;
+        self.dis_ch(0);
+        self.dis_ch(1);
     }
 
     /// enable a TEC channel via shutdown pin.
     pub fn en_ch(&mut self, ch: u8) {
+        if self.inhibited {
+            return;
+        }
         if ch == 0 {
             self.shdn0.set_high().unwrap();
         } else {
             self.shdn1.set_high().unwrap();
         }
+        self.enabled[ch as usize] = true;
+    }
+
+    /// Check if a TEC channel is enabled.
+    pub fn is_enabled(&self, ch: u8) -> bool {
+        self.enabled[ch as usize]
     }
 
     /// disable a TEC channel via shutdown pin.
@@ -227,5 +295,6 @@ impl Dacs {
         } else {
             self.shdn1.set_low().unwrap();
         }
+        self.enabled[ch as usize] = false;
     }
 }
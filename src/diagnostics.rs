@@ -0,0 +1,139 @@
+///! Internal diagnostics
+///!
+///! # Design
+///! The diagnostics cycle through the measurements of the AD7172 temperature sensor, its supply
+///! and the external reference. The measurements do not block: each update collects the result of
+///! the previous measurement, which the ADC converts interleaved with the control channels, and
+///! starts the next one. The external reference is measured against the supply, which is in turn
+///! measured against the internal 2.5 V reference of the ADC. The cycle completes with the
+///! internal reference and die temperature of the STM32 and yields a diagnostics message.
+///!
+///! The thermistor readings assume a stable external reference. A warning is raised if the
+///! reference drifts from its value at the first measurement after boot.
+use num_traits::float::Float;
+use serde::Serialize;
+
+use crate::adc::{Adc, Diagnostic};
+use crate::tec_monitor::TecMonitor;
+use crate::unit_conversion::{adc_to_die_temp, adc_to_ratio, adc_to_supply};
+
+/// The relative drift of the external reference that raises a warning.
+const MAX_REFERENCE_DRIFT: f32 = 1e-3;
+
+/// The ADC measurements of a cycle.
+const MEASUREMENTS: [Diagnostic; 3] = [
+    Diagnostic::Temperature,
+    Diagnostic::Supply,
+    Diagnostic::Reference,
+];
+
+/// Diagnostics as published on `<prefix>/diagnostics`.
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct Diagnostics {
+    /// AD7172 temperature in °C.
+    pub adc_temperature: f32,
+    /// AD7172 supply voltage (AVDD1 - AVSS) in V.
+    pub adc_supply: f32,
+    /// External AD7172 reference voltage in V.
+    pub adc_reference: f32,
+    /// Relative drift of the external reference since boot.
+    pub reference_drift: f32,
+    /// Indicates that the reference drift exceeds the limit.
+    pub reference_warning: bool,
+    /// STM32 internal reference (VREFINT) voltage in V.
+    pub mcu_vrefint: f32,
+    /// STM32 die temperature in °C.
+    pub mcu_temperature: f32,
+}
+
+pub struct DiagnosticsMonitor {
+    diagnostics: Diagnostics,
+    /// The raw results of the ADC measurements of the current cycle.
+    results: [u32; MEASUREMENTS.len()],
+    /// The next ADC measurement.
+    next: usize,
+    /// Indicates that an ADC measurement is pending.
+    pending: bool,
+    /// The external reference voltage at the first measurement.
+    initial_reference: Option<f32>,
+}
+
+impl DiagnosticsMonitor {
+    pub const fn new() -> Self {
+        Self {
+            diagnostics: Diagnostics {
+                adc_temperature: 0.0,
+                adc_supply: 0.0,
+                adc_reference: 0.0,
+                reference_drift: 0.0,
+                reference_warning: false,
+                mcu_vrefint: 0.0,
+                mcu_temperature: 0.0,
+            },
+            results: [0; MEASUREMENTS.len()],
+            next: 0,
+            pending: false,
+            initial_reference: None,
+        }
+    }
+
+    /// Collect the result of the pending measurement and start the next one.
+    ///
+    /// # Note
+    /// The ADC abandons measurements without result after a few conversion sequences, so the
+    /// wait scales with the ADC rate.
+    ///
+    /// # Args
+    /// * `adc` - The AD7172.
+    /// * `mcu` - The monitor owning the ADC of the STM32.
+    ///
+    /// # Returns
+    /// The diagnostics after a cycle of measurements completed.
+    pub fn update(&mut self, adc: &mut Adc, mcu: &mut TecMonitor) -> Option<Diagnostics> {
+        if self.pending {
+            match adc.take_measurement() {
+                Some(Ok(result)) => {
+                    self.results[self.next] = result;
+                    self.next += 1;
+                }
+                // Restart the cycle, so all values are consistent.
+                Some(Err(_)) => self.next = 0,
+                None => return None,
+            }
+            self.pending = false;
+        }
+
+        if self.next < MEASUREMENTS.len() {
+            adc.start_measurement(MEASUREMENTS[self.next]);
+            self.pending = true;
+            return None;
+        }
+        self.next = 0;
+
+        let [temperature, supply, reference] = self.results;
+        let diagnostics = &mut self.diagnostics;
+        diagnostics.adc_temperature = adc_to_die_temp(temperature);
+        diagnostics.adc_supply = adc_to_supply(supply);
+        diagnostics.adc_reference = adc_to_ratio(reference) * diagnostics.adc_supply;
+
+        let initial = *self
+            .initial_reference
+            .get_or_insert(diagnostics.adc_reference);
+        diagnostics.reference_drift = (diagnostics.adc_reference - initial) / initial;
+        let warning = diagnostics.reference_drift.abs() > MAX_REFERENCE_DRIFT;
+        if warning && !diagnostics.reference_warning {
+            log::warn!(
+                "ADC reference drifted from {} V to {} V, temperatures are biased",
+                initial,
+                diagnostics.adc_reference
+            );
+        }
+        diagnostics.reference_warning = warning;
+
+        let (vrefint, temperature) = mcu.measure_mcu();
+        diagnostics.mcu_vrefint = vrefint;
+        diagnostics.mcu_temperature = temperature;
+
+        Some(*diagnostics)
+    }
+}
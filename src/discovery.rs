@@ -0,0 +1,276 @@
+///! Home Assistant MQTT discovery
+///!
+///! # Design
+///! Home Assistant creates entities from retained config messages on
+///! `homeassistant/<component>/<node>/<object>/config`. Each thermostat channel is described by:
+///! * a temperature, a TEC current and a TEC current setpoint sensor, which extract their state
+///!   from the telemetry messages with a value template,
+///! * a number entity for the target temperature and a switch for engaging the controller, which
+///!   take their state from the retained active settings `<prefix>/settings_active/<path>` and
+///!   command the setting topics `<prefix>/settings/<path>`.
+///!
+///! All entities belong to one device, identified by its MAC address, and report as unavailable
+///! while `<prefix>/alive` indicates that the device is offline.
+///!
+///! # Note
+///! The value templates require JSON telemetry encoding.
+use core::fmt::Write;
+use heapless::String;
+use serde::Serialize;
+use smoltcp_nal::smoltcp::wire::EthernetAddress;
+
+use crate::network_users::ACTIVE_SETTINGS_TOPIC;
+use crate::telemetry::TelemetryClient;
+
+/// The Home Assistant discovery prefix.
+const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// The range of the target temperature number entity in degrees Celsius.
+const TARGET_MIN: f32 = -20.0;
+const TARGET_MAX: f32 = 80.0;
+const TARGET_STEP: f32 = 0.01;
+
+/// The kinds of entities published per channel.
+#[derive(Copy, Clone)]
+enum Entity {
+    Temperature,
+    Current,
+    CurrentSetpoint,
+    Target,
+    Engage,
+}
+
+const ENTITIES: [Entity; 5] = [
+    Entity::Temperature,
+    Entity::Current,
+    Entity::CurrentSetpoint,
+    Entity::Target,
+    Entity::Engage,
+];
+
+#[derive(Serialize)]
+struct Device<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    manufacturer: &'a str,
+    model: &'a str,
+    sw_version: &'a str,
+    hw_version: &'a str,
+}
+
+#[derive(Serialize)]
+struct Config<'a> {
+    name: &'a str,
+    unique_id: &'a str,
+    state_topic: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_template: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    step: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_on: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_off: Option<&'a str>,
+    availability_topic: &'a str,
+    payload_available: &'a str,
+    payload_not_available: &'a str,
+    device: Device<'a>,
+}
+
+/// Publishes the discovery config messages one entity per update after every (re-)connection.
+pub struct DiscoveryPublisher {
+    prefix: String<128>,
+    node: String<12>,
+    name: String<64>,
+    next: Option<usize>,
+}
+
+impl DiscoveryPublisher {
+    /// Construct a new discovery publisher.
+    ///
+    /// # Args
+    /// * `prefix` - The MQTT prefix of the device.
+    /// * `app` - The name of the application.
+    /// * `mac` - The MAC address of the device.
+    /// * `alias` - The device alias, used as device name unless empty.
+    pub fn new(prefix: &str, app: &str, mac: EthernetAddress, alias: &str) -> Self {
+        let mut node: String<12> = String::new();
+        for byte in mac.as_bytes() {
+            write!(&mut node, "{:02x}", byte).unwrap();
+        }
+
+        let mut name: String<64> = String::new();
+        if alias.is_empty() {
+            write!(&mut name, "{} {}", app, mac).ok();
+        } else {
+            name.push_str(alias).ok();
+        }
+
+        Self {
+            prefix: String::from(prefix),
+            node,
+            name,
+            next: None,
+        }
+    }
+
+    /// Request a publication of all config messages.
+    pub fn request(&mut self) {
+        self.next = Some(0);
+    }
+
+    /// Publish the next pending config message, if any.
+    pub fn update<T: Serialize>(&mut self, client: &mut TelemetryClient<T>) {
+        let index = match self.next {
+            Some(index) if client.is_connected() => index,
+            _ => return,
+        };
+
+        if index >= 2 * ENTITIES.len() {
+            self.next = None;
+            return;
+        }
+
+        let channel = index / ENTITIES.len();
+        let entity = ENTITIES[index % ENTITIES.len()];
+
+        let mut topic: String<128> = String::new();
+        let mut payload = [0u8; 768];
+        let len = match self.encode(channel, entity, &mut topic, &mut payload) {
+            Ok(len) => len,
+            Err(_) => {
+                log::warn!("Discovery config {} does not fit", index);
+                self.next = Some(index + 1);
+                return;
+            }
+        };
+
+        // A failed publication is retried during the next update.
+        if client.publish_retained(&topic, &payload[..len]).is_ok() {
+            self.next = Some(index + 1);
+        }
+    }
+
+    /// Encode the config message of an entity.
+    ///
+    /// # Returns
+    /// The length of the payload.
+    fn encode(
+        &self,
+        channel: usize,
+        entity: Entity,
+        topic: &mut String<128>,
+        payload: &mut [u8],
+    ) -> Result<usize, ()> {
+        let (component, object, label) = match entity {
+            Entity::Temperature => ("sensor", "temperature", "Temperature"),
+            Entity::Current => ("sensor", "current", "TEC current"),
+            Entity::CurrentSetpoint => ("sensor", "current_setpoint", "TEC current setpoint"),
+            Entity::Target => ("number", "target", "Target temperature"),
+            Entity::Engage => ("switch", "engage", "Engage"),
+        };
+        write!(
+            topic,
+            "{}/{}/{}/{}_{}/config",
+            DISCOVERY_PREFIX, component, self.node, object, channel
+        )
+        .map_err(|_| ())?;
+
+        let mut name: String<64> = String::new();
+        write!(&mut name, "{} {}", label, channel).map_err(|_| ())?;
+        let mut unique_id: String<64> = String::new();
+        write!(&mut unique_id, "{}_{}_{}", self.node, object, channel).map_err(|_| ())?;
+        let mut availability_topic: String<192> = String::new();
+        write!(&mut availability_topic, "{}/alive", self.prefix).map_err(|_| ())?;
+
+        let mut state_topic: String<192> = String::new();
+        let mut command_topic: String<192> = String::new();
+        let mut value_template: String<64> = String::new();
+        match entity {
+            Entity::Temperature | Entity::Current | Entity::CurrentSetpoint => {
+                write!(&mut state_topic, "{}/telemetry", self.prefix).map_err(|_| ())?;
+                let field = match entity {
+                    Entity::Temperature => "adcs",
+                    Entity::Current => "tec_current",
+                    _ => "dacs",
+                };
+                write!(
+                    &mut value_template,
+                    "{{{{ value_json.{}[{}] }}}}",
+                    field, channel
+                )
+                .map_err(|_| ())?;
+            }
+            Entity::Target | Entity::Engage => {
+                let mut path: String<32> = String::new();
+                match entity {
+                    Entity::Target => write!(&mut path, "pidsettings/{}/target", channel),
+                    _ => write!(&mut path, "engage_iir/{}", channel),
+                }
+                .map_err(|_| ())?;
+                write!(
+                    &mut state_topic,
+                    "{}/{}/{}",
+                    self.prefix, ACTIVE_SETTINGS_TOPIC, path
+                )
+                .map_err(|_| ())?;
+                write!(&mut command_topic, "{}/settings/{}", self.prefix, path).map_err(|_| ())?;
+            }
+        }
+
+        let (device_class, unit) = match entity {
+            Entity::Temperature | Entity::Target => (Some("temperature"), Some("°C")),
+            Entity::Current | Entity::CurrentSetpoint => (Some("current"), Some("A")),
+            Entity::Engage => (None, None),
+        };
+        let is_control = matches!(entity, Entity::Target | Entity::Engage);
+        let is_number = matches!(entity, Entity::Target);
+        let is_switch = matches!(entity, Entity::Engage);
+
+        let config = Config {
+            name: &name,
+            unique_id: &unique_id,
+            state_topic: &state_topic,
+            command_topic: if is_control {
+                Some(&command_topic)
+            } else {
+                None
+            },
+            value_template: if value_template.is_empty() {
+                None
+            } else {
+                Some(&value_template)
+            },
+            device_class,
+            unit_of_measurement: unit,
+            min: if is_number { Some(TARGET_MIN) } else { None },
+            max: if is_number { Some(TARGET_MAX) } else { None },
+            step: if is_number { Some(TARGET_STEP) } else { None },
+            payload_on: if is_switch { Some("true") } else { None },
+            payload_off: if is_switch { Some("false") } else { None },
+            availability_topic: &availability_topic,
+            payload_available: "1",
+            payload_not_available: "0",
+            device: Device {
+                identifiers: [&self.node],
+                name: &self.name,
+                manufacturer: "Sinara",
+                model: "Thermostat",
+                sw_version: env!("CARGO_PKG_VERSION"),
+                hw_version: crate::HARDWARE_REVISION,
+            },
+        };
+
+        serde_json_core::to_slice(&config, payload).map_err(|_| ())
+    }
+}
@@ -0,0 +1,365 @@
+///! Minimal DNS resolver
+///!
+///! # Design
+///! The resolver sends a single recursive query for the IPv4 address (A record) of a host name to
+///! a DNS server over UDP and parses the first A record of the answer. It is non-blocking: a query
+///! is started with `query()`, and `poll()` is called regularly to send it once the stack has room
+///! and to obtain the result. Unanswered queries time out. CNAME chains are followed by the
+///! recursive server and the A records are returned in the same answer.
+///!
+///! Without a configured server, the router of the default route is queried. With DHCP, that is
+///! the router of the lease. The network stack does not expose the DNS servers of the lease, so
+///! the router has to forward DNS queries in that case.
+use byteorder::{BigEndian, ByteOrder};
+use heapless::Vec;
+use minimq::embedded_nal::{nb, IpAddr, Ipv4Addr, SocketAddr, UdpClientStack};
+use smoltcp_nal::smoltcp::wire::{IpAddress, IpCidr};
+
+use crate::network_users::NetworkReference;
+use crate::system_timer::SystemTimer;
+
+const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT_MS: u64 = 2_000;
+const MAX_MESSAGE_SIZE: usize = 512;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const FLAGS_RD: u16 = 1 << 8; // Recursion desired
+const FLAGS_QR: u16 = 1 << 15; // Response
+const FLAGS_RCODE: u16 = 0xF;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DnsError {
+    /// The host name is not a valid DNS name.
+    InvalidName,
+    /// No DNS server is configured and there is no default route.
+    NoServer,
+    /// The network stack failed to send or receive.
+    Network,
+    /// The server did not answer in time.
+    Timeout,
+    /// The server answered with an error or without an A record.
+    NotFound,
+    /// The answer could not be parsed.
+    Malformed,
+}
+
+type UdpSocket = <NetworkReference as UdpClientStack>::UdpSocket;
+
+pub struct Resolver {
+    stack: NetworkReference,
+    socket: Option<UdpSocket>,
+    /// The query of the pending resolution.
+    message: Vec<u8, MAX_MESSAGE_SIZE>,
+    /// Indicates that the query still has to be sent.
+    unsent: bool,
+    id: u16,
+    deadline: u64,
+}
+
+impl Resolver {
+    /// Construct a new resolver.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the (shared) underlying network stack.
+    pub fn new(stack: NetworkReference) -> Self {
+        Self {
+            stack,
+            socket: None,
+            message: Vec::new(),
+            unsent: false,
+            id: 0,
+            deadline: 0,
+        }
+    }
+
+    /// Check if a query is in progress.
+    pub fn is_pending(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// Start resolving a host name. Aborts any pending query.
+    ///
+    /// # Note
+    /// The query is sent by `poll()` if the stack can not send it right away.
+    ///
+    /// # Args
+    /// * `server` - The address of the DNS server to query. `None` to query the router of the
+    ///   default route.
+    /// * `host` - The host name to resolve.
+    pub fn query(&mut self, server: Option<Ipv4Addr>, host: &str) -> Result<(), DnsError> {
+        self.abort();
+
+        self.id = self.id.wrapping_add(1);
+        self.message = encode_query(self.id, host)?;
+        let server = server.or_else(|| self.router()).ok_or(DnsError::NoServer)?;
+
+        let stack = &mut self.stack;
+        let mut socket = UdpClientStack::socket(stack).map_err(|_| DnsError::Network)?;
+        if UdpClientStack::connect(
+            stack,
+            &mut socket,
+            SocketAddr::new(IpAddr::V4(server), DNS_PORT),
+        )
+        .is_err()
+        {
+            UdpClientStack::close(stack, socket).ok();
+            return Err(DnsError::Network);
+        }
+
+        self.socket.replace(socket);
+        self.unsent = true;
+        self.deadline = SystemTimer::millis() + QUERY_TIMEOUT_MS;
+        match self.send() {
+            Err(nb::Error::Other(e)) => {
+                self.abort();
+                Err(e)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the router of the default route.
+    fn router(&mut self) -> Option<Ipv4Addr> {
+        let mut router = None;
+        self.stack.lock(|stack| {
+            stack.interface_mut().routes_mut().update(|routes| {
+                let default = IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0);
+                if let Some(route) = routes.get(&default) {
+                    if let IpAddress::Ipv4(address) = route.via_router {
+                        router = Some(Ipv4Addr::from(address.0));
+                    }
+                }
+            })
+        });
+        router
+    }
+
+    /// Send the query if it was not sent yet.
+    fn send(&mut self) -> nb::Result<(), DnsError> {
+        let socket = match self.socket.as_mut() {
+            Some(socket) if self.unsent => socket,
+            _ => return Ok(()),
+        };
+        match UdpClientStack::send(&mut self.stack, socket, &self.message) {
+            Ok(()) => {
+                self.unsent = false;
+                Ok(())
+            }
+            Err(nb::Error::WouldBlock) => Err(nb::Error::WouldBlock),
+            Err(nb::Error::Other(_)) => Err(nb::Error::Other(DnsError::Network)),
+        }
+    }
+
+    /// Poll for the result of a pending query.
+    ///
+    /// # Returns
+    /// The result of the query once it completed, `None` while it is in progress or if no query is
+    /// pending.
+    pub fn poll(&mut self) -> Option<Result<Ipv4Addr, DnsError>> {
+        self.socket.as_ref()?;
+
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let result = match self.send() {
+            Err(nb::Error::Other(e)) => Some(Err(e)),
+            Err(nb::Error::WouldBlock) => None,
+            Ok(()) => self.receive(&mut buf),
+        };
+
+        let result = match result {
+            None if SystemTimer::millis() > self.deadline => Some(Err(DnsError::Timeout)),
+            result => result,
+        };
+
+        if result.is_some() {
+            self.abort();
+        }
+        result
+    }
+
+    /// Receive the answer to the sent query.
+    fn receive(&mut self, buf: &mut [u8]) -> Option<Result<Ipv4Addr, DnsError>> {
+        // Note(unwrap): Only called while a query is pending.
+        let socket = self.socket.as_mut().unwrap();
+        match UdpClientStack::receive(&mut self.stack, socket, buf) {
+            Ok((len, _)) => match parse_response(self.id, &buf[..len]) {
+                // Ignore stray messages, e.g. late answers to earlier queries.
+                Err(DnsError::Malformed) => None,
+                result => Some(result),
+            },
+            Err(nb::Error::WouldBlock) => None,
+            Err(nb::Error::Other(_)) => Some(Err(DnsError::Network)),
+        }
+    }
+
+    /// Abort a pending query.
+    pub fn abort(&mut self) {
+        self.unsent = false;
+        if let Some(socket) = self.socket.take() {
+            UdpClientStack::close(&mut self.stack, socket).ok();
+        }
+    }
+}
+
+/// Encode a recursive query for the A record of a host name.
+fn encode_query(id: u16, host: &str) -> Result<Vec<u8, MAX_MESSAGE_SIZE>, DnsError> {
+    let mut message: Vec<u8, MAX_MESSAGE_SIZE> = Vec::new();
+    let mut header = [0u8; 12];
+    BigEndian::write_u16(&mut header[0..2], id);
+    BigEndian::write_u16(&mut header[2..4], FLAGS_RD);
+    BigEndian::write_u16(&mut header[4..6], 1); // QDCOUNT
+    message.extend_from_slice(&header).unwrap();
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+        message
+            .push(label.len() as u8)
+            .map_err(|_| DnsError::InvalidName)?;
+        message
+            .extend_from_slice(label.as_bytes())
+            .map_err(|_| DnsError::InvalidName)?;
+    }
+
+    let mut question = [0u8; 5];
+    BigEndian::write_u16(&mut question[1..3], TYPE_A);
+    BigEndian::write_u16(&mut question[3..5], CLASS_IN);
+    message
+        .extend_from_slice(&question)
+        .map_err(|_| DnsError::InvalidName)?;
+
+    Ok(message)
+}
+
+/// Skip over a (possibly compressed) name.
+///
+/// # Returns
+/// The offset of the first byte after the name.
+fn skip_name(message: &[u8], mut offset: usize) -> Result<usize, DnsError> {
+    loop {
+        let len = *message.get(offset).ok_or(DnsError::Malformed)? as usize;
+        match len {
+            0 => return Ok(offset + 1),
+            // A compression pointer terminates the name.
+            l if l & 0xC0 == 0xC0 => return Ok(offset + 2),
+            l => offset += 1 + l,
+        }
+    }
+}
+
+/// Parse the response to a query and extract the first A record.
+fn parse_response(id: u16, message: &[u8]) -> Result<Ipv4Addr, DnsError> {
+    if message.len() < 12 || BigEndian::read_u16(&message[0..2]) != id {
+        return Err(DnsError::Malformed);
+    }
+    let flags = BigEndian::read_u16(&message[2..4]);
+    if flags & FLAGS_QR == 0 {
+        return Err(DnsError::Malformed);
+    }
+    if flags & FLAGS_RCODE != 0 {
+        return Err(DnsError::NotFound);
+    }
+    let questions = BigEndian::read_u16(&message[4..6]);
+    let answers = BigEndian::read_u16(&message[6..8]);
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4; // QTYPE, QCLASS
+    }
+
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+        let record = message
+            .get(offset..offset + 10)
+            .ok_or(DnsError::Malformed)?;
+        let rtype = BigEndian::read_u16(&record[0..2]);
+        let class = BigEndian::read_u16(&record[2..4]);
+        let len = BigEndian::read_u16(&record[8..10]) as usize;
+        offset += 10;
+
+        let data = message
+            .get(offset..offset + len)
+            .ok_or(DnsError::Malformed)?;
+        if rtype == TYPE_A && class == CLASS_IN && len == 4 {
+            return Ok(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+        }
+        offset += len;
+    }
+
+    Err(DnsError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Turn a query into a response with the given answer records.
+    fn respond(query: &[u8], answers: &[&[u8]]) -> Vec<u8, MAX_MESSAGE_SIZE> {
+        let mut message: Vec<u8, MAX_MESSAGE_SIZE> = Vec::from_slice(query).unwrap();
+        BigEndian::write_u16(&mut message[2..4], FLAGS_QR | FLAGS_RD | 1 << 7);
+        BigEndian::write_u16(&mut message[6..8], answers.len() as u16);
+        for answer in answers {
+            message.extend_from_slice(answer).unwrap();
+        }
+        message
+    }
+
+    #[test]
+    fn query() {
+        let query = encode_query(0x1234, "broker.lan.").unwrap();
+        assert_eq!(
+            &query[..],
+            &[
+                0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 6, b'b',
+                b'r', b'o', b'k', b'e', b'r', 3, b'l', b'a', b'n', 0, 0x00, 0x01, 0x00, 0x01,
+            ]
+        );
+        assert_eq!(skip_name(&query, 12), Ok(query.len() - 4));
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert_eq!(encode_query(0, "broker..lan"), Err(DnsError::InvalidName));
+        assert_eq!(encode_query(0, ""), Err(DnsError::InvalidName));
+        let label = [b'a'; 64];
+        let host = core::str::from_utf8(&label).unwrap();
+        assert_eq!(encode_query(0, host), Err(DnsError::InvalidName));
+    }
+
+    #[test]
+    fn response() {
+        let query = encode_query(7, "broker.lan").unwrap();
+        // A CNAME to a name in the question followed by the A record of that name, both with
+        // compressed names.
+        let cname = [
+            0xC0, 12, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x02, 0xC0, 19,
+        ];
+        let a = [
+            0xC0, 19, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x04, 10, 0, 0, 1,
+        ];
+        let response = respond(&query, &[&cname, &a]);
+        assert_eq!(parse_response(7, &response), Ok(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(parse_response(8, &response), Err(DnsError::Malformed));
+    }
+
+    #[test]
+    fn errors() {
+        let query = encode_query(7, "broker.lan").unwrap();
+        // The query itself is not a response.
+        assert_eq!(parse_response(7, &query), Err(DnsError::Malformed));
+
+        let mut response = respond(&query, &[]);
+        assert_eq!(parse_response(7, &response), Err(DnsError::NotFound));
+        response[3] |= 3; // NXDOMAIN
+        assert_eq!(parse_response(7, &response), Err(DnsError::NotFound));
+
+        // An answer claiming more data than the message holds.
+        let a = [
+            0xC0, 12, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3C, 0x00, 0x04, 10,
+        ];
+        let response = respond(&query, &[&a]);
+        assert_eq!(parse_response(7, &response), Err(DnsError::Malformed));
+        assert_eq!(parse_response(7, &response[..20]), Err(DnsError::Malformed));
+    }
+}
@@ -0,0 +1,98 @@
+///! Fixed capacity string settings
+///!
+///! # Design
+///! Settings are `Copy` and are addressed by miniconf. `heapless::String` provides neither, so
+///! string valued settings (e.g. host names) use this fixed capacity, `Copy` string instead. It
+///! serializes to and deserializes from a plain JSON string and is updated atomically by miniconf.
+use core::fmt;
+use miniconf::Miniconf;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct FixedString<const N: usize> {
+    len: usize,
+    data: [u8; N],
+}
+
+impl<const N: usize> FixedString<N> {
+    /// Construct a string from a `str`.
+    ///
+    /// # Returns
+    /// The string or `None` if `s` exceeds the capacity.
+    pub fn new(s: &str) -> Option<Self> {
+        let mut data = [0; N];
+        data.get_mut(..s.len())?.copy_from_slice(s.as_bytes());
+        Some(Self { len: s.len(), data })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Note(unwrap): The data is always copied from a valid `str`.
+        core::str::from_utf8(&self.data[..self.len]).unwrap()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<const N: usize> Default for FixedString<N> {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            data: [0; N],
+        }
+    }
+}
+
+impl<const N: usize> fmt::Debug for FixedString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> fmt::Display for FixedString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> Serialize for FixedString<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+struct FixedStringVisitor<const N: usize>;
+
+impl<'de, const N: usize> de::Visitor<'de> for FixedStringVisitor<N> {
+    type Value = FixedString<N>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a string of at most {} bytes", N)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        FixedString::new(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for FixedString<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(FixedStringVisitor)
+    }
+}
+
+impl<const N: usize> Miniconf for FixedString<N> {
+    fn string_set(
+        &mut self,
+        mut topic_parts: core::iter::Peekable<core::str::Split<char>>,
+        value: &[u8],
+    ) -> Result<(), miniconf::Error> {
+        if topic_parts.peek().is_some() {
+            return Err(miniconf::Error::NameTooLong);
+        }
+
+        *self = serde_json_core::from_slice(value)?.0;
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+// Thermostat settings storage in internal flash
+//
+// The settings are stored as a single record in the flash sector reserved for configuration data
+// (`CONFIG` in `memory.x`, sector 12 of the STM32F427). The record consists of a header with a
+// magic number, the settings layout version, the payload length and a CRC32 of the payload,
+// followed by the JSON serialized settings.
+//
+// The configuration sector is in bank 2 of the flash, while the firmware executes from bank 1. So
+// the sector is erased and programmed in the background while the firmware continues to run.
+// A save is started with `save` and advanced by polling until it completes.
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::{info, warn};
+use minimq::embedded_nal::nb;
+use serde::{de::DeserializeOwned, Serialize};
+
+use stm32_eth::stm32::FLASH;
+
+// Flash unlock key sequence.
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+// Flash CR register bits.
+const CR_PG: u32 = 1 << 0;
+const CR_SER: u32 = 1 << 1;
+const CR_SNB_SHIFT: u32 = 3;
+const CR_PSIZE_X32: u32 = 0b10 << 8;
+const CR_STRT: u32 = 1 << 16;
+const CR_LOCK: u32 = 1 << 31;
+
+// Flash SR register bits.
+const SR_ERRORS: u32 = 0b1111_0010; // PGSERR, PGPERR, PGAERR, WRPERR, OPERR
+const SR_BSY: u32 = 1 << 16;
+
+/// Sector number of the configuration sector. Sector 12 is the first sector of bank 2, which is
+/// encoded with bit 4 set.
+const CONFIG_SECTOR: u32 = 0b1_0000;
+const CONFIG_SIZE: usize = 16 * 1024;
+
+const MAGIC: u32 = 0x5448_5354; // "THST"
+const HEADER_SIZE: usize = 16;
+const MAX_PAYLOAD_SIZE: usize = 2048;
+const RECORD_SIZE: usize = HEADER_SIZE + MAX_PAYLOAD_SIZE;
+
+/// Number of words programmed per poll. Programming a word takes about 16 us.
+const WORDS_PER_POLL: usize = 64;
+
+extern "C" {
+    // Start of the configuration flash region, provided by `memory.x`.
+    static _config_start: u8;
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum FlashError {
+    /// The settings could not be serialized into the payload buffer.
+    Serialization,
+    /// The flash controller reported an error. Contains the SR register value.
+    Controller(u32),
+    /// A save is already in progress.
+    Busy,
+}
+
+/// The progress of a save in the background.
+#[derive(Copy, Clone, Debug)]
+struct PendingSave {
+    /// The size of the record to program in bytes.
+    size: usize,
+    /// The number of bytes programmed. Zero while the sector is erased.
+    programmed: usize,
+}
+
+pub struct Flash {
+    flash: FLASH,
+    record: [u8; RECORD_SIZE],
+    pending: Option<PendingSave>,
+}
+
+impl Flash {
+    pub fn new(flash: FLASH) -> Self {
+        Flash {
+            flash,
+            record: [0; RECORD_SIZE],
+            pending: None,
+        }
+    }
+
+    /// The memory mapped configuration sector.
+    fn config(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(&_config_start as *const u8, CONFIG_SIZE) }
+    }
+
+    /// Load settings from flash.
+    ///
+    /// # Args
+    /// * `version` - The settings layout version. Records with a different version are ignored.
+    ///
+    /// # Returns
+    /// The stored settings or `None` if no valid record exists.
+    pub fn load<S: DeserializeOwned>(&self, version: u32) -> Option<S> {
+        let config = self.config();
+        let header = &config[..HEADER_SIZE];
+        if LittleEndian::read_u32(&header[0..4]) != MAGIC {
+            info!("No stored settings");
+            return None;
+        }
+        let stored_version = LittleEndian::read_u32(&header[4..8]);
+        if stored_version != version {
+            warn!(
+                "Ignoring stored settings version {} (expected {})",
+                stored_version, version
+            );
+            return None;
+        }
+        let len = LittleEndian::read_u32(&header[8..12]) as usize;
+        if len > MAX_PAYLOAD_SIZE {
+            warn!("Stored settings corrupt: invalid length {}", len);
+            return None;
+        }
+        let payload = &config[HEADER_SIZE..HEADER_SIZE + len];
+        if crc32(payload) != LittleEndian::read_u32(&header[12..16]) {
+            warn!("Stored settings corrupt: CRC mismatch");
+            return None;
+        }
+        match serde_json_core::from_slice(payload) {
+            Ok((settings, _)) => {
+                info!("Loaded stored settings");
+                Some(settings)
+            }
+            Err(e) => {
+                warn!("Stored settings invalid: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Start storing settings in flash, replacing any previous record.
+    ///
+    /// # Note
+    /// This only starts erasing the sector. The save is completed by calling `poll` until it
+    /// does not block anymore. The erase takes several hundred milliseconds.
+    ///
+    /// # Args
+    /// * `version` - The settings layout version.
+    /// * `settings` - The settings to store.
+    pub fn save<S: Serialize>(&mut self, version: u32, settings: &S) -> Result<(), FlashError> {
+        if self.pending.is_some() {
+            return Err(FlashError::Busy);
+        }
+
+        self.record.fill(0);
+        let len = serde_json_core::to_slice(settings, &mut self.record[HEADER_SIZE..])
+            .map_err(|_| FlashError::Serialization)?;
+        let crc = crc32(&self.record[HEADER_SIZE..HEADER_SIZE + len]);
+        LittleEndian::write_u32(&mut self.record[0..4], MAGIC);
+        LittleEndian::write_u32(&mut self.record[4..8], version);
+        LittleEndian::write_u32(&mut self.record[8..12], len as u32);
+        LittleEndian::write_u32(&mut self.record[12..16], crc);
+
+        self.unlock();
+        if let Err(e) = self.erase_config() {
+            self.lock();
+            warn!("Storing settings failed: {:?}", e);
+            return Err(e);
+        }
+
+        // Program whole words. The padding is part of the (zeroed) record buffer.
+        self.pending = Some(PendingSave {
+            size: (HEADER_SIZE + len + 3) & !3,
+            programmed: 0,
+        });
+        info!("Storing settings ({} bytes)", len);
+        Ok(())
+    }
+
+    /// Advance a save in progress.
+    ///
+    /// # Note
+    /// Each call programs at most `WORDS_PER_POLL` words, blocking for about a millisecond.
+    ///
+    /// # Returns
+    /// `WouldBlock` while the save is in progress, `Ok` once it completed or if there is none.
+    pub fn poll(&mut self) -> nb::Result<(), FlashError> {
+        let pending = match self.pending {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        if self.is_busy() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let end = pending.size.min(pending.programmed + 4 * WORDS_PER_POLL);
+        let result = self
+            .check_errors()
+            .and_then(|_| self.program(pending.programmed, end));
+        match result {
+            Ok(()) if end < pending.size => {
+                self.pending = Some(PendingSave {
+                    programmed: end,
+                    ..pending
+                });
+                Err(nb::Error::WouldBlock)
+            }
+            Ok(()) => {
+                self.pending = None;
+                self.lock();
+                info!("Stored settings");
+                Ok(())
+            }
+            Err(e) => {
+                self.pending = None;
+                self.lock();
+                warn!("Storing settings failed: {:?}", e);
+                Err(nb::Error::Other(e))
+            }
+        }
+    }
+
+    /// Check that no save is in progress.
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    fn unlock(&mut self) {
+        if self.flash.cr.read().bits() & CR_LOCK != 0 {
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.flash.cr.write(|w| unsafe { w.bits(CR_LOCK) });
+    }
+
+    fn is_busy(&self) -> bool {
+        self.flash.sr.read().bits() & SR_BSY != 0
+    }
+
+    /// Check the previous flash operation for errors.
+    fn check_errors(&mut self) -> Result<(), FlashError> {
+        let sr = self.flash.sr.read().bits();
+        if sr & SR_ERRORS != 0 {
+            // Clear the error flags (write one to clear).
+            self.flash.sr.write(|w| unsafe { w.bits(SR_ERRORS) });
+            return Err(FlashError::Controller(sr));
+        }
+        Ok(())
+    }
+
+    /// Wait for the current flash operation to finish and check for errors.
+    fn wait(&mut self) -> Result<(), FlashError> {
+        while self.is_busy() {}
+        self.check_errors()
+    }
+
+    /// Start erasing the configuration sector.
+    fn erase_config(&mut self) -> Result<(), FlashError> {
+        self.wait()?;
+        self.flash
+            .cr
+            .write(|w| unsafe { w.bits(CR_PSIZE_X32 | CR_SER | CONFIG_SECTOR << CR_SNB_SHIFT) });
+        self.flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_STRT) });
+        Ok(())
+    }
+
+    /// Program a word aligned range of the record.
+    ///
+    /// # Args
+    /// * `start` - The offset of the range in bytes.
+    /// * `end` - The end of the range in bytes.
+    fn program(&mut self, start: usize, end: usize) -> Result<(), FlashError> {
+        self.wait()?;
+        self.flash
+            .cr
+            .write(|w| unsafe { w.bits(CR_PSIZE_X32 | CR_PG) });
+        let base = self.config().as_ptr() as *mut u32;
+        for offset in (start..end).step_by(4) {
+            let word = LittleEndian::read_u32(&self.record[offset..offset + 4]);
+            unsafe {
+                core::ptr::write_volatile(base.add(offset / 4), word);
+            }
+            self.wait()?;
+        }
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3) of a byte slice.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
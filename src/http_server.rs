@@ -0,0 +1,295 @@
+///! Minimal HTTP/1.0 server
+///!
+///! # Design
+///! The server handles a single request per connection and closes the connection after the
+///! response was sent. It serves:
+///! * `GET /` - A status page, which periodically loads and shows the telemetry.
+///! * `GET /telemetry` - The most recent telemetry as JSON.
+///! * `GET /settings` - All active settings as JSON.
+///! * `GET /settings/<path>` - The JSON value of the setting at the miniconf path.
+///! * `PUT /settings/<path>` - Set the setting at the miniconf path to the JSON value in the body.
+///!   The change is rejected with the reason if the resulting settings are invalid.
+///!
+///! Settings changed via HTTP are applied and published the same way as settings received over
+///! MQTT.
+use core::fmt::Write;
+use heapless::{String, Vec};
+use miniconf::Miniconf;
+use serde::Serialize;
+
+use crate::network_users::{NetworkReference, NetworkState};
+use crate::settings_tree::{self, Validate};
+use crate::tcp_server::TcpServer;
+
+const MAX_REQUEST_SIZE: usize = 512;
+const MAX_RESPONSE_SIZE: usize = 3072;
+
+const STATUS_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Thermostat</title></head>
+<body style="font-family:sans-serif">
+<h1>Thermostat</h1>
+<table>
+<tr><th></th><th>Channel 0</th><th>Channel 1</th></tr>
+<tr><td>Temperature (&deg;C)</td><td id="t0"></td><td id="t1"></td></tr>
+<tr><td>TEC current (A)</td><td id="i0"></td><td id="i1"></td></tr>
+<tr><td>Measured TEC current (A)</td><td id="m0"></td><td id="m1"></td></tr>
+<tr><td>TEC power (W)</td><td id="p0"></td><td id="p1"></td></tr>
+</table>
+<h2>Telemetry</h2>
+<pre id="telemetry">Waiting for telemetry...</pre>
+<p><a href="/settings">Settings</a></p>
+<script>
+function update() {
+  fetch("/telemetry").then(r => r.json()).then(t => {
+    for (const ch of [0, 1]) {
+      document.getElementById("t" + ch).textContent = t.adcs[ch].toFixed(3);
+      document.getElementById("i" + ch).textContent = t.dacs[ch].toFixed(3);
+      document.getElementById("m" + ch).textContent = t.tec_current[ch].toFixed(3);
+      document.getElementById("p" + ch).textContent = t.tec_power[ch].toFixed(3);
+    }
+    document.getElementById("telemetry").textContent = JSON.stringify(t, null, 2);
+  }).catch(() => {});
+}
+update();
+setInterval(update, 1000);
+</script>
+</body>
+</html>
+"#;
+
+/// The status of an HTTP response.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Status {
+    Ok,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    ServiceUnavailable,
+}
+
+impl Status {
+    fn line(&self) -> &'static str {
+        match self {
+            Status::Ok => "200 OK",
+            Status::BadRequest => "400 Bad Request",
+            Status::NotFound => "404 Not Found",
+            Status::MethodNotAllowed => "405 Method Not Allowed",
+            Status::PayloadTooLarge => "413 Payload Too Large",
+            Status::ServiceUnavailable => "503 Service Unavailable",
+        }
+    }
+}
+
+pub struct HttpServer {
+    server: TcpServer,
+    request: Vec<u8, MAX_REQUEST_SIZE>,
+    response: Vec<u8, MAX_RESPONSE_SIZE>,
+    /// Indicates that the response is complete and the connection is closed once it was sent.
+    responded: bool,
+}
+
+impl HttpServer {
+    /// Construct a new server.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the (shared) underlying network stack.
+    /// * `port` - The TCP port to listen on. Zero disables the server.
+    pub fn new(stack: NetworkReference, port: u16) -> Self {
+        Self {
+            server: TcpServer::new(stack, port),
+            request: Vec::new(),
+            response: Vec::new(),
+            responded: false,
+        }
+    }
+
+    /// Serve the connected client.
+    ///
+    /// # Args
+    /// * `settings` - The active settings.
+    /// * `telemetry` - The most recent telemetry as JSON. Empty if there is none yet.
+    ///
+    /// # Returns
+    /// `SettingsChanged` if the settings were modified.
+    pub fn update<S: Miniconf + Serialize + Copy + Validate>(
+        &mut self,
+        settings: &mut S,
+        telemetry: &[u8],
+    ) -> Option<NetworkState> {
+        if !self.server.poll() {
+            self.reset();
+            return None;
+        }
+
+        if self.responded {
+            if let Ok(sent) = self.server.send(&self.response) {
+                let rest = self.response.len() - sent;
+                self.response.copy_within(sent.., 0);
+                self.response.truncate(rest);
+            }
+            if self.response.is_empty() {
+                self.server.close();
+                self.reset();
+            }
+            return None;
+        }
+
+        let len = self.request.len();
+        // Note(unwrap): The request never exceeds its capacity.
+        self.request.resize(MAX_REQUEST_SIZE, 0).unwrap();
+        let received = self.server.receive(&mut self.request[len..]);
+        self.request.truncate(len + received.unwrap_or(0));
+        if received.is_err() {
+            return None;
+        }
+
+        let request = core::mem::take(&mut self.request);
+        let (status, state) = match parse(&request) {
+            Ok(Some((method, path, body))) => self.handle(method, path, body, settings, telemetry),
+            Ok(None) if !request.is_full() => {
+                self.request = request;
+                return None;
+            }
+            Ok(None) => (self.error(Status::PayloadTooLarge), None),
+            Err(()) => (self.error(Status::BadRequest), None),
+        };
+
+        log::info!("HTTP response: {}", status.line());
+        self.responded = true;
+        state
+    }
+
+    /// Handle a complete request and queue the response.
+    fn handle<S: Miniconf + Serialize + Copy + Validate>(
+        &mut self,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        settings: &mut S,
+        telemetry: &[u8],
+    ) -> (Status, Option<NetworkState>) {
+        let setting = path.strip_prefix("/settings/");
+
+        let status = match (method, path, setting) {
+            ("GET", "/", _) => self.respond(Status::Ok, "text/html", STATUS_PAGE.as_bytes()),
+            ("GET", "/telemetry", _) => {
+                if telemetry.is_empty() {
+                    self.error(Status::ServiceUnavailable)
+                } else {
+                    self.respond(Status::Ok, "application/json", telemetry)
+                }
+            }
+            ("GET", "/settings", _) => {
+                let mut buf = [0u8; MAX_RESPONSE_SIZE - 128];
+                match serde_json_core::to_slice(settings, &mut buf) {
+                    Ok(len) => self.respond(Status::Ok, "application/json", &buf[..len]),
+                    Err(_) => self.error(Status::ServiceUnavailable),
+                }
+            }
+            ("GET", _, Some(setting)) => {
+                let mut value: Vec<u8, { settings_tree::MAX_VALUE_SIZE }> = Vec::new();
+                match settings_tree::get(settings, setting, &mut value) {
+                    Ok(true) => self.respond(Status::Ok, "application/json", &value),
+                    Ok(false) => self.error(Status::NotFound),
+                    Err(_) => self.error(Status::ServiceUnavailable),
+                }
+            }
+            ("PUT", _, Some(setting)) => {
+                // Only report success for settings that are applied, so check a copy first.
+                let mut updated = *settings;
+                match updated.string_set(setting.split('/').peekable(), body) {
+                    Ok(()) => match updated.validate() {
+                        Ok(()) => {
+                            *settings = updated;
+                            let status = self.respond(Status::Ok, "text/plain", b"ok\n");
+                            return (status, Some(NetworkState::SettingsChanged));
+                        }
+                        Err(reason) => {
+                            let mut body: String<64> = String::new();
+                            writeln!(&mut body, "{}", reason).ok();
+                            self.respond(Status::BadRequest, "text/plain", body.as_bytes())
+                        }
+                    },
+                    Err(error) => {
+                        log::warn!("Settings update of {} failed: {:?}", setting, error);
+                        self.error(Status::BadRequest)
+                    }
+                }
+            }
+            (_, "/", _) | (_, "/telemetry", _) | (_, "/settings", _) | (_, _, Some(_)) => {
+                self.error(Status::MethodNotAllowed)
+            }
+            _ => self.error(Status::NotFound),
+        };
+
+        (status, None)
+    }
+
+    /// Queue an error response.
+    fn error(&mut self, status: Status) -> Status {
+        let mut body: String<64> = String::new();
+        writeln!(&mut body, "{}", status.line()).unwrap();
+        self.respond(status, "text/plain", body.as_bytes())
+    }
+
+    /// Queue a response.
+    fn respond(&mut self, status: Status, content_type: &str, body: &[u8]) -> Status {
+        let mut head: String<128> = String::new();
+        write!(
+            &mut head,
+            "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status.line(),
+            content_type,
+            body.len()
+        )
+        .unwrap();
+
+        self.response.clear();
+        // Note(unwrap): Bodies are bounded to fit into the response with the head.
+        self.response.extend_from_slice(head.as_bytes()).unwrap();
+        self.response.extend_from_slice(body).unwrap();
+        status
+    }
+
+    fn reset(&mut self) {
+        self.request.clear();
+        self.response.clear();
+        self.responded = false;
+    }
+}
+
+/// Parse the received request.
+///
+/// # Returns
+/// The method, path and body of the request or `None` if it is incomplete.
+fn parse(request: &[u8]) -> Result<Option<(&str, &str, &[u8])>, ()> {
+    let end = match request.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let head = core::str::from_utf8(&request[..end]).map_err(|_| ())?;
+    let body = &request[end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().ok_or(())?.split(' ');
+    let method = request_line.next().ok_or(())?;
+    let path = request_line.next().ok_or(())?;
+
+    let mut content_length = 0;
+    for line in lines {
+        let mut header = line.splitn(2, ':');
+        let name = header.next().ok_or(())?;
+        if name.eq_ignore_ascii_case("content-length") {
+            let value = header.next().ok_or(())?.trim();
+            content_length = value.parse::<usize>().map_err(|_| ())?;
+        }
+    }
+
+    if body.len() < content_length {
+        return Ok(None);
+    }
+
+    Ok(Some((method, path, &body[..content_length])))
+}
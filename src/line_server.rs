@@ -0,0 +1,217 @@
+///! Line based TCP command interface
+///!
+///! # Design
+///! A TCP server accepting a single client at a time. The client sends newline terminated commands
+///! and receives one newline terminated response per command:
+///! * `get <path>` - Responds with the JSON value of the setting at the miniconf path.
+///! * `set <path> <value>` - Sets the setting at the miniconf path to the JSON value. The change is
+///!   rejected with the reason if the resulting settings are invalid.
+///! * `telemetry` - Responds with the most recent telemetry message as JSON.
+///! * `<command> [payload]` - Executes the device command of the same name, e.g. `save`, `reset`
+///!   or `calibrate {"channel": 0, "calibration": "internal_zero"}`. The rest of the line is the
+///!   command payload.
+///!
+///! Successful commands without a value respond with `ok`, failures with `error: <reason>`. The
+///! response to `save` additionally notes that the settings are stored in the background. The
+///! settings are the ones managed by the MQTT settings client, so changes are applied and
+///! published the same way as settings received over MQTT.
+use heapless::Vec;
+use miniconf::Miniconf;
+use serde::Serialize;
+
+use crate::commands::Command;
+use crate::network_users::{NetworkReference, NetworkState};
+use crate::settings_tree::{self, Validate};
+use crate::tcp_server::TcpServer;
+
+const MAX_LINE_LENGTH: usize = 256;
+const MAX_OUTPUT_SIZE: usize = 1024;
+
+pub struct LineServer {
+    server: TcpServer,
+    input: Vec<u8, MAX_LINE_LENGTH>,
+    output: Vec<u8, MAX_OUTPUT_SIZE>,
+}
+
+impl LineServer {
+    /// Construct a new server.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the (shared) underlying network stack.
+    /// * `port` - The TCP port to listen on. Zero disables the server.
+    pub fn new(stack: NetworkReference, port: u16) -> Self {
+        Self {
+            server: TcpServer::new(stack, port),
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Serve the connected client.
+    ///
+    /// # Note
+    /// At most one command is processed per update, so resulting state changes are never
+    /// superseded by later commands before they are handled.
+    ///
+    /// # Args
+    /// * `settings` - The active settings.
+    /// * `telemetry` - The most recent telemetry as JSON. Empty if there is none yet.
+    ///
+    /// # Returns
+    /// `SettingsChanged` if the settings were modified, `Command` if a command is to be executed.
+    pub fn update<S: Miniconf + Serialize + Copy + Validate>(
+        &mut self,
+        settings: &mut S,
+        telemetry: &[u8],
+    ) -> Option<NetworkState> {
+        if !self.server.poll() {
+            self.input.clear();
+            self.output.clear();
+            return None;
+        }
+
+        self.flush();
+
+        // Only accept the next command once the previous response was sent.
+        if !self.output.is_empty() {
+            return None;
+        }
+
+        let len = self.input.len();
+        // Note(unwrap): The input never exceeds its capacity.
+        self.input.resize(MAX_LINE_LENGTH, 0).unwrap();
+        let received = self.server.receive(&mut self.input[len..]);
+        self.input.truncate(len + received.unwrap_or(0));
+        if received.is_err() {
+            return None;
+        }
+
+        let end = match self.input.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None => {
+                if self.input.is_full() {
+                    self.input.clear();
+                    self.respond(b"error: line too long");
+                }
+                return None;
+            }
+        };
+
+        let mut line: Vec<u8, MAX_LINE_LENGTH> = Vec::new();
+        // Note(unwrap): The line is part of the input, which has the same capacity.
+        line.extend_from_slice(&self.input[..end]).unwrap();
+        let rest = self.input.len() - (end + 1);
+        self.input.copy_within(end + 1.., 0);
+        self.input.truncate(rest);
+
+        let result = match core::str::from_utf8(&line) {
+            Ok(line) => self.execute(line.trim(), settings, telemetry),
+            Err(_) => {
+                self.respond(b"error: invalid UTF-8");
+                None
+            }
+        };
+        self.flush();
+        result
+    }
+
+    /// Execute a single command line and queue its response.
+    fn execute<S: Miniconf + Serialize + Copy + Validate>(
+        &mut self,
+        line: &str,
+        settings: &mut S,
+        telemetry: &[u8],
+    ) -> Option<NetworkState> {
+        let mut words = line.splitn(3, ' ');
+        let command = words.next().unwrap_or("");
+        let path = words.next();
+        let value = words.next().map(str::trim);
+
+        match (command, path, value) {
+            ("", None, None) => None,
+            ("get", Some(path), None) => {
+                let mut value: Vec<u8, { settings_tree::MAX_VALUE_SIZE }> = Vec::new();
+                match settings_tree::get(settings, path, &mut value) {
+                    Ok(true) => self.respond(&value),
+                    Ok(false) => self.respond(b"error: unknown path"),
+                    Err(_) => self.respond(b"error: setting unavailable"),
+                }
+                None
+            }
+            ("set", Some(path), Some(value)) => {
+                // Only report success for settings that are applied, so check a copy first.
+                let mut updated = *settings;
+                match updated.string_set(path.split('/').peekable(), value.as_bytes()) {
+                    Ok(()) => match updated.validate() {
+                        Ok(()) => {
+                            *settings = updated;
+                            self.respond(b"ok");
+                            Some(NetworkState::SettingsChanged)
+                        }
+                        Err(reason) => {
+                            self.respond_error(reason);
+                            None
+                        }
+                    },
+                    Err(error) => {
+                        log::warn!("Settings update of {} failed: {:?}", path, error);
+                        self.respond(b"error: invalid path or value");
+                        None
+                    }
+                }
+            }
+            ("telemetry", None, None) => {
+                if telemetry.is_empty() {
+                    self.respond(b"error: no telemetry");
+                } else {
+                    self.respond(telemetry);
+                }
+                None
+            }
+            (name, _, _) => {
+                let payload = line[name.len()..].trim();
+                match Command::parse(name, payload.as_bytes()) {
+                    Some(command) => {
+                        self.respond(match command {
+                            Command::Save => &b"ok: saving in the background, takes about 1 s"[..],
+                            _ => &b"ok"[..],
+                        });
+                        Some(NetworkState::Command(command))
+                    }
+                    None => {
+                        self.respond(b"error: unknown command or invalid payload");
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Check that all responses were handed to the network stack.
+    pub fn is_flushed(&self) -> bool {
+        self.output.is_empty()
+    }
+
+    /// Queue a response line.
+    fn respond(&mut self, response: &[u8]) {
+        // Note(unwrap): Responses are bounded by the line, value and telemetry sizes.
+        self.output.extend_from_slice(response).unwrap();
+        self.output.push(b'\n').unwrap();
+    }
+
+    /// Queue an error response line with a reason.
+    fn respond_error(&mut self, reason: &str) {
+        // Note(unwrap): Reasons are short static descriptions.
+        self.output.extend_from_slice(b"error: ").unwrap();
+        self.respond(reason.as_bytes());
+    }
+
+    /// Send as much of the queued output as possible.
+    fn flush(&mut self) {
+        if let Ok(sent) = self.server.send(&self.output) {
+            let rest = self.output.len() - sent;
+            self.output.copy_within(sent.., 0);
+            self.output.truncate(rest);
+        }
+    }
+}
@@ -1,39 +1,84 @@
-#![no_std]
-#![no_main]
+// The unit tests of the hardware independent modules run on the host with std, e.g. with
+// `cargo test --target x86_64-unknown-linux-gnu`. The application itself is not built for them.
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 use log::info;
+#[cfg(not(test))]
 use panic_halt as _;
 
 mod adc;
+mod broker;
+mod commands;
 mod dac;
+mod diagnostics;
+mod discovery;
+mod dns;
+mod fixed_string;
+mod flash;
+mod http_server;
 mod leds;
+mod line_server;
+mod mdns;
+mod miniconf_client;
+mod msgpack;
 mod network_users;
+mod reference_points;
+mod self_test;
+mod settings_tree;
 mod setup;
 mod shared;
+mod system_timer;
+mod tcp_server;
+mod tec_monitor;
 mod telemetry;
 mod unit_conversion;
 
-use adc::Adc;
+use adc::{Adc, AdcMode, DIAGNOSTIC_CHANNEL};
+use commands::Command;
 use dac::{Dacs, Pwms};
+use diagnostics::DiagnosticsMonitor;
+use fixed_string::FixedString;
+use flash::{Flash, FlashError};
 use idsp::iir;
 use leds::Leds;
 
 use miniconf::Miniconf;
+use minimq::embedded_nal::nb;
 use network_users::{NetworkState, NetworkUsers};
+use reference_points::ReferencePoints;
 use rtic::cyccnt::U32Ext as _;
-use serde::Deserialize;
+use self_test::SelfTestReport;
+use serde::{Deserialize, Serialize};
+use settings_tree::Validate;
 use stm32_eth;
 use stm32_eth::stm32::Peripherals;
-use telemetry::{Telemetry, TelemetryBuffer};
-use unit_conversion::{i_to_dac, pid_to_iir, temp_to_iiroffset, MAXI, VREF_DAC, VREF_TEC};
+use system_timer::SystemTimer;
+use tec_monitor::TecMonitor;
+use telemetry::{Telemetry, TelemetryBuffer, TelemetryEncoding};
+use unit_conversion::{
+    adc_to_temp, dac_to_i, i_to_dac, pid_to_iir, temp_to_iiroffset, MAXI, MAXV, VREF_DAC, VREF_TEC,
+};
 
 const IIR_CASCADE_LENGTH: usize = 1; // Number of concatenated IIRs. Settings only support one right now.
 const CYC_PER_S: u32 = 168_000_000; // 168MHz main clock
 const LED_PERIOD: u32 = CYC_PER_S / 2; // LED blinking period
 const ETH_P_PERIOD: u32 = CYC_PER_S / 1000; // Ethernet polling period
+const ADC_CHECK_PERIOD: u32 = CYC_PER_S; // ADC configuration read-back period
+const TEC_READBACK_PERIOD: u32 = CYC_PER_S / 10; // TEC current and voltage readback period
+const DIAGNOSTICS_PERIOD: u32 = 2 * CYC_PER_S; // Period of the individual diagnostic measurements
+const MIN_ADC_RATE: f32 = 1.0 / MAX_PERIOD; // Minimum conversion sequence rate in the triggered ADC mode
+const ADC_MODE_POLL_PERIOD: u32 = CYC_PER_S / 10; // Period of checking for the triggered ADC mode
+const FLASH_POLL_PERIOD: u32 = CYC_PER_S / 1000; // Period of advancing a settings save in flash
+const RESET_DELAY: u32 = CYC_PER_S / 10; // Time for the network stack to transmit command responses
+const RESET_TIMEOUT: u32 = 2000; // Maximum number of flash and response checks before a reset
+const MAX_PERIOD: f32 = 10.0; // Longest task period in s. Periods of 2^31 cycles or more wrap around.
 const OUTSCALE: f32 = 131072.0 * VREF_TEC / (VREF_DAC / 2.0); // Output scale. Zero current is slightly off center.
+pub const SETTINGS_VERSION: u32 = 13; // Version of the stored settings layout. Increment on changes to `Settings`.
+pub const HARDWARE_REVISION: &str = "v2.0"; // Thermostat hardware revision reported in the device identity.
 
-#[derive(Copy, Clone, Debug, Deserialize, Miniconf)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Miniconf)]
 pub struct PidSettings {
     pub pid: [f32; 3],
     pub target: f32,
@@ -41,7 +86,7 @@ pub struct PidSettings {
     pub max_i_pos: f32,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Miniconf)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Miniconf)]
 pub struct AdcFilterSettings {
     pub odr: u32,
     pub order: u32,
@@ -49,21 +94,148 @@ pub struct AdcFilterSettings {
     pub enhfilten: u32,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Miniconf)]
+/// Auxiliary ADC channel measured against the internal 2.5 V reference.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Miniconf)]
+pub struct AuxChannelSettings {
+    pub enable: bool,
+    /// The positive analog input as in the AINPOS field of the ADC CHx register.
+    pub ainpos: u32,
+    /// The negative analog input as in the AINNEG field of the ADC CHx register.
+    pub ainneg: u32,
+}
+
+/// ADC offset and gain coefficients of a channel as in the OFFSETx and GAINx registers.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize, Miniconf)]
+pub struct AdcCalibration {
+    /// Offset coefficient. Zero to use the power-on value.
+    pub offset: u32,
+    /// Gain coefficient. Zero to use the factory calibrated value.
+    pub gain: u32,
+}
+
+/// Linear temperature correction of a channel `T = gain * T_measured + offset` against a
+/// reference thermometer.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Miniconf)]
+pub struct TemperatureCalibration {
+    /// Gain. Must be positive.
+    pub gain: f32,
+    /// Offset in °C.
+    pub offset: f32,
+}
+
+impl Default for TemperatureCalibration {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            offset: 0.0,
+        }
+    }
+}
+
+/// Network configuration. Changes only take effect after they are saved and the device is reset.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Miniconf)]
+pub struct NetworkSettings {
+    /// Acquire the address and gateway via DHCP instead of using `ip`, `netmask` and `gateway`.
+    pub dhcp: bool,
+    /// Override of the MAC address. All zero to derive it from the unique device ID, which is
+    /// also used if the override is not a unicast address.
+    pub mac: [u8; 6],
+    pub ip: [u8; 4],
+    pub netmask: [u8; 4],
+    pub gateway: [u8; 4],
+    /// The DNS server used to resolve the broker host name. All zero to use the gateway, or the
+    /// router of the lease with DHCP.
+    pub dns: [u8; 4],
+    /// The broker IPv4 address or host name.
+    pub broker: FixedString<64>,
+    /// The broker used if the primary one is unreachable. Empty to disable failover.
+    pub secondary_broker: FixedString<64>,
+    /// A human-friendly name of the device. Must not contain `/`, `+` or `#`.
+    pub alias: FixedString<32>,
+    /// The MQTT prefix template. `{app}`, `{mac}` and `{alias}` are replaced by the application
+    /// name, the MAC address and the alias. The resulting prefix must not contain `+`, `#` or
+    /// empty topic levels, so `{alias}` requires a non-empty alias.
+    pub prefix: FixedString<64>,
+    /// The TCP port of the line based command interface. Zero to disable it.
+    pub tcp_port: u16,
+    /// The TCP port of the HTTP server. Zero to disable it.
+    pub http_port: u16,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        let broker = FixedString::new(option_env!("BROKER").unwrap_or("10.42.0.1")).unwrap();
+        Self {
+            dhcp: false,
+            mac: [0, 0, 0, 0, 0, 0],
+            ip: [10, 42, 0, 18],
+            netmask: [255, 255, 255, 0],
+            gateway: [0, 0, 0, 0],
+            dns: [0, 0, 0, 0],
+            broker,
+            secondary_broker: FixedString::default(),
+            alias: FixedString::default(),
+            prefix: FixedString::new(network_users::DEFAULT_PREFIX_TEMPLATE).unwrap(),
+            tcp_port: 5025,
+            http_port: 80,
+        }
+    }
+}
+
+impl NetworkSettings {
+    /// Check if the addressing (IP, netmask, gateway, DHCP and brokers) is the default one.
+    pub fn has_default_addressing(&self) -> bool {
+        let default = Self::default();
+        self.dhcp == default.dhcp
+            && self.ip == default.ip
+            && self.netmask == default.netmask
+            && self.gateway == default.gateway
+            && self.broker == default.broker
+            && self.secondary_broker == default.secondary_broker
+    }
+
+    /// The prefix length of the netmask.
+    ///
+    /// # Returns
+    /// The number of leading one bits or `None` if the netmask is not contiguous.
+    pub fn prefix_len(&self) -> Option<u8> {
+        let mask = u32::from_be_bytes(self.netmask);
+        // The inverted mask must be of the form 0..01..1.
+        if !mask & (!mask).wrapping_add(1) != 0 {
+            return None;
+        }
+        Some(mask.count_ones() as u8)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Miniconf)]
 pub struct Settings {
     telemetry_period: f32,
+    telemetry_encoding: TelemetryEncoding,
     led: bool,
     dacs: [f32; 2],
     pidsettings: [PidSettings; 2],
     engage_iir: [bool; 2],
     adcsettings: AdcFilterSettings,
+    adc_mode: AdcMode,
+    /// Conversion sequence rate in Hz in the triggered ADC mode. This is the control loop rate.
+    /// At least 0.1 Hz and at most the rate of the conversion sequence including the auxiliary
+    /// and diagnostic channels.
+    adc_rate: f32,
+    adc_calibration: [AdcCalibration; 2],
+    temperature_calibration: [TemperatureCalibration; 2],
+    aux: [AuxChannelSettings; 2],
+    /// Number of control channel conversion sequences between auxiliary channel samples.
+    aux_interval: u32,
     max_v_tec: [f32; 2],
+    network: NetworkSettings,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
             telemetry_period: 1.0,
+            telemetry_encoding: TelemetryEncoding::Json,
             led: false,
             dacs: [0.0, 0.0],
             engage_iir: [false, false],
@@ -73,7 +245,18 @@ impl Default for Settings {
                 enhfilt: 0b110, // 16.67 SPS, 92 dB rejection, 60 ms settling
                 enhfilten: 0,   // disable postfilter
             },
+            adc_mode: AdcMode::Continuous,
+            adc_rate: 1.0,
+            adc_calibration: [AdcCalibration::default(); 2],
+            temperature_calibration: [TemperatureCalibration::default(); 2],
+            aux: [AuxChannelSettings {
+                enable: false,
+                ainpos: 0b00100, // AIN4
+                ainneg: 0b10110, // REF-
+            }; 2],
+            aux_interval: 10,
             max_v_tec: [1.0, 1.0],
+            network: NetworkSettings::default(),
             pidsettings: [
                 PidSettings {
                     pid: [1.0, 0., 0.],
@@ -92,6 +275,56 @@ impl Default for Settings {
     }
 }
 
+impl Validate for Settings {
+    fn validate(&self) -> Result<(), &'static str> {
+        if !(0.01..=MAX_PERIOD).contains(&self.telemetry_period) {
+            return Err("telemetry period out of range");
+        }
+        let conversion_time =
+            adc::conversion_time(&self.adcsettings).ok_or("ADC filter settings reserved")?;
+        // The trigger period must stay below 2^31 cycles and must not be shorter than a
+        // conversion sequence, which a trigger would restart. The sequence includes an enabled
+        // first auxiliary channel and the second one, which is shared with the diagnostics.
+        let channels = 3 + self.aux[0].enable as u32;
+        let max_adc_rate = 1.0 / (channels as f32 * conversion_time);
+        if !(MIN_ADC_RATE..=max_adc_rate).contains(&self.adc_rate) {
+            return Err("ADC rate out of range");
+        }
+        if !self.dacs.iter().all(|i| (-MAXI..=MAXI).contains(i)) {
+            return Err("DAC current out of range");
+        }
+        for pid in self.pidsettings.iter() {
+            if !pid.pid.iter().all(|k| k.is_finite()) || !pid.target.is_finite() {
+                return Err("PID gains or target not finite");
+            }
+            if !(0.0..=MAXI).contains(&pid.max_i_neg) || !(0.0..=MAXI).contains(&pid.max_i_pos) {
+                return Err("current limit out of range");
+            }
+        }
+        if !self.max_v_tec.iter().all(|v| (0.0..=MAXV).contains(v)) {
+            return Err("voltage limit out of range");
+        }
+        for calibration in self.temperature_calibration.iter() {
+            if !calibration.gain.is_finite() || calibration.gain <= 0.0 {
+                return Err("temperature calibration gain not positive");
+            }
+            if !calibration.offset.is_finite() {
+                return Err("temperature calibration offset not finite");
+            }
+        }
+        if self.network.prefix_len().is_none() {
+            return Err("netmask not contiguous");
+        }
+        network_users::validate_prefix(
+            self.network.prefix.as_str(),
+            env!("CARGO_BIN_NAME"),
+            self.network.alias.as_str(),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(not(test))]
 #[rtic::app(device = stm32_eth::stm32, peripherals = true, monotonic = rtic::cyccnt::CYCCNT)]
 const APP: () = {
     struct Resources {
@@ -99,37 +332,66 @@ const APP: () = {
         adc: Adc,
         dacs: Dacs,
         pwms: Pwms,
+        tec_monitor: TecMonitor,
+        flash: Flash,
         iirs: [[iir::IIR<f64>; IIR_CASCADE_LENGTH]; 2],
         #[init([[[0.; 5]; IIR_CASCADE_LENGTH]; 2])]
         iir_state: [[iir::Vec5<f64>; IIR_CASCADE_LENGTH]; 2],
         network: NetworkUsers<Settings, Telemetry>,
         settings: Settings,
+        /// The settings stored in flash.
+        stored_settings: Settings,
+        /// Indicates that a command changed the calibrations, which are stored once applied.
+        #[init(false)]
+        calibration_changed: bool,
         telemetry: TelemetryBuffer,
+        self_test: SelfTestReport,
+        #[init([ReferencePoints::new(), ReferencePoints::new()])]
+        references: [ReferencePoints; 2],
+        #[init(DiagnosticsMonitor::new())]
+        diagnostics: DiagnosticsMonitor,
     }
 
-    #[init(schedule = [blink, poll_eth, process, tele], spawn = [settings_update])]
+    #[init(schedule = [blink, poll_eth, process, tele, check_adc, tec_readback, diagnostics, trigger_adc], spawn = [settings_update])]
     fn init(c: init::Context) -> init::LateResources {
         let thermostat = setup::setup(c.core, c.device);
 
+        let network_settings = thermostat.network_devices.settings;
         let network = NetworkUsers::new(
             thermostat.network_devices.stack,
             env!("CARGO_BIN_NAME"),
             thermostat.network_devices.mac_address,
-            option_env!("BROKER")
-                .unwrap_or("10.42.0.1")
-                .parse()
-                .unwrap(),
+            &network_settings,
+            thermostat.settings,
+            &thermostat.self_test,
+            // Only a custom addressing can fall back to the default one.
+            !network_settings.has_default_addressing(),
         );
 
         log::info!("Network users done");
 
-        let settings = Settings::default();
+        let settings = thermostat.settings;
 
         c.schedule.blink(c.start + LED_PERIOD.cycles()).unwrap();
         c.schedule
             .poll_eth(c.start + ETH_P_PERIOD.cycles())
             .unwrap();
         c.schedule.tele(c.start + CYC_PER_S.cycles()).unwrap();
+        c.schedule
+            .tec_readback(c.start + TEC_READBACK_PERIOD.cycles())
+            .unwrap();
+        // A missing ADC can not be reconfigured.
+        if thermostat.self_test.adc {
+            c.schedule
+                .check_adc(c.start + ADC_CHECK_PERIOD.cycles())
+                .unwrap();
+            c.schedule
+                .diagnostics(c.start + DIAGNOSTICS_PERIOD.cycles())
+                .unwrap();
+            c.schedule
+                .trigger_adc(c.start + ADC_MODE_POLL_PERIOD.cycles())
+                .unwrap();
+        }
 
         // apply default settings
         c.spawn.settings_update().unwrap();
@@ -139,10 +401,14 @@ const APP: () = {
             adc: thermostat.adc,
             dacs: thermostat.dacs,
             pwms: thermostat.pwms,
+            tec_monitor: thermostat.tec_monitor,
+            flash: thermostat.flash,
             iirs: [[iir::IIR::new(1., 0.0, 0.0); IIR_CASCADE_LENGTH]; 2],
             network,
             settings,
+            stored_settings: thermostat.settings,
             telemetry: TelemetryBuffer::default(),
+            self_test: thermostat.self_test,
         }
     }
 
@@ -170,14 +436,44 @@ const APP: () = {
         telemetry.dacs = dacs.val;
     }
 
-    #[task(priority = 1, resources=[network, settings, dacs, adc, pwms, iirs])]
+    #[task(priority = 1, resources=[network, settings, stored_settings, calibration_changed, dacs, adc, pwms, iirs], spawn = [save_settings])]
     fn settings_update(c: settings_update::Context) {
         log::info!("updating settings");
-        let settings = c.resources.network.miniconf.settings();
+        let network = c.resources.network;
+        let calibration_changed = core::mem::take(c.resources.calibration_changed);
+        if let Err(error) = network.miniconf.settings().validate() {
+            log::warn!("Rejected settings: {}", error);
+            // Revert to the active settings.
+            *network.miniconf.settings_mut() = *c.resources.settings;
+            return;
+        }
+        network.publish_settings();
 
+        let settings = network.miniconf.settings();
         *c.resources.settings = *settings;
 
+        // Store the calibrations changed by a command, so they are restored on boot. Other
+        // unsaved settings still require the `Save` command.
+        if calibration_changed {
+            let stored = c.resources.stored_settings;
+            stored.adc_calibration = settings.adc_calibration;
+            stored.temperature_calibration = settings.temperature_calibration;
+            if c.spawn.save_settings(Some(*stored)).is_err() {
+                log::warn!("Settings save dropped");
+            }
+        }
+
+        network.telemetry.set_encoding(settings.telemetry_encoding);
+
         c.resources.adc.set_filters(settings.adcsettings);
+        c.resources.adc.set_acquisition(settings.adc_mode);
+        c.resources
+            .adc
+            .set_aux(&settings.aux, settings.aux_interval);
+        for (ch, calibration) in settings.adc_calibration.iter().enumerate() {
+            // ADC ch1 is Thermostat ch0
+            c.resources.adc.set_calibration(1 - ch as u8, *calibration);
+        }
 
         c.resources.pwms.set_all(
             // set currents to 5% of max higher in order to avoid railing the driver before the filter.
@@ -196,7 +492,10 @@ const APP: () = {
                 .zip(pid_to_iir(settings.pidsettings[i].pid).iter())
                 .map(|(d, x)| *d = *x as f64)
                 .last();
-            iir[0].set_x_offset(temp_to_iiroffset(settings.pidsettings[i].target) as f64); // set output offset to input target
+            iir[0].set_x_offset(temp_to_iiroffset(
+                settings.pidsettings[i].target,
+                &settings.temperature_calibration[i],
+            ) as f64); // set output offset to input target
             iir[0].y_min = (i_to_dac(-settings.pidsettings[i].max_i_neg) as f32 - OUTSCALE) as f64;
             iir[0].y_max = (i_to_dac(settings.pidsettings[i].max_i_pos) as f32 - OUTSCALE) as f64;
         }
@@ -216,36 +515,225 @@ const APP: () = {
         }
     }
 
-    #[task(priority = 1, resources = [network], schedule = [poll_eth],  spawn=[settings_update])]
+    #[task(priority = 1, resources = [network, stored_settings, calibration_changed, adc, telemetry, references], spawn = [settings_update, save_settings, reset])]
+    fn command(c: command::Context, command: Command) {
+        log::info!("Executing command: {:?}", command);
+        match command {
+            Command::Save => {
+                let settings = *c.resources.network.miniconf.settings();
+                *c.resources.stored_settings = settings;
+                if c.spawn.save_settings(Some(settings)).is_err() {
+                    log::warn!("Settings save dropped");
+                }
+            }
+            Command::Reset => {
+                c.spawn.reset(RESET_TIMEOUT).ok();
+            }
+            Command::Calibrate(args) => {
+                // ADC ch1 is Thermostat ch0. The result is handled by `calibrated`.
+                c.resources
+                    .adc
+                    .start_calibration(1 - args.channel, args.calibration);
+            }
+            Command::AddReference(args) => {
+                let ch = args.channel as usize;
+                if c.resources.telemetry.samples[ch] == 0 {
+                    log::warn!("No temperature measured on channel {}", ch);
+                    return;
+                }
+                let measured = adc_to_temp(
+                    c.resources.telemetry.adcs[ch],
+                    &TemperatureCalibration::default(),
+                );
+                match c.resources.references[ch].add(measured, args.temperature) {
+                    Ok(calibration) => {
+                        log::info!(
+                            "Temperature correction of channel {}: {:?}",
+                            ch,
+                            calibration
+                        );
+                        let network = c.resources.network;
+                        network.miniconf.settings_mut().temperature_calibration[ch] = calibration;
+                        *c.resources.calibration_changed = true;
+                        c.spawn.settings_update().ok();
+                    }
+                    Err(error) => log::warn!("Reference point rejected: {:?}", error),
+                }
+            }
+            Command::ClearReferences(args) => {
+                let ch = args.channel as usize;
+                c.resources.references[ch].clear();
+                let network = c.resources.network;
+                network.miniconf.settings_mut().temperature_calibration[ch] =
+                    TemperatureCalibration::default();
+                *c.resources.calibration_changed = true;
+                c.spawn.settings_update().ok();
+            }
+        }
+    }
+
+    // Apply the result of an ADC calibration started by a command.
+    #[task(priority = 1, resources = [network, calibration_changed], spawn = [settings_update])]
+    fn calibrated(c: calibrated::Context, ch: usize, coefficients: AdcCalibration) {
+        c.resources.network.miniconf.settings_mut().adc_calibration[ch] = coefficients;
+        *c.resources.calibration_changed = true;
+        // A pending update applies the new settings as well.
+        c.spawn.settings_update().ok();
+    }
+
+    // Store settings in flash in the background. Starts a save if settings are given and then
+    // advances the save until it completed, so the erase does not block the other tasks.
+    #[task(priority = 1, capacity = 4, resources = [flash], schedule = [save_settings])]
+    fn save_settings(c: save_settings::Context, settings: Option<Settings>) {
+        let now = rtic::cyccnt::Instant::now();
+        if let Some(settings) = settings {
+            match c.resources.flash.save(SETTINGS_VERSION, &settings) {
+                Ok(()) => {}
+                // Retry once the save in progress completed.
+                Err(FlashError::Busy) => {
+                    c.schedule
+                        .save_settings(now + FLASH_POLL_PERIOD.cycles(), Some(settings))
+                        .ok();
+                    return;
+                }
+                Err(_) => return,
+            }
+        }
+
+        if let Err(nb::Error::WouldBlock) = c.resources.flash.poll() {
+            if c.schedule
+                .save_settings(now + FLASH_POLL_PERIOD.cycles(), None)
+                .is_err()
+            {
+                log::warn!("Settings save stalled");
+            }
+        }
+    }
+
+    // Reset the device once the command responses were sent and a settings save completed.
+    // Waits at most `remaining` checks, e.g. if a client stops receiving.
+    #[task(priority = 1, resources = [network, flash], schedule = [reset])]
+    fn reset(c: reset::Context, remaining: u32) {
+        if remaining == 0 {
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        let idle = c.resources.flash.is_idle();
+        let flushed = c.resources.network.is_flushed();
+        let now = rtic::cyccnt::Instant::now();
+        let next = if idle && flushed {
+            // The responses were handed to the network stack, which still has to transmit them.
+            c.schedule.reset(now + RESET_DELAY.cycles(), 0)
+        } else {
+            c.schedule
+                .reset(now + FLASH_POLL_PERIOD.cycles(), remaining - 1)
+        };
+        if next.is_err() {
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+
+    #[task(priority = 1, resources = [network], schedule = [poll_eth],  spawn=[settings_update, command])]
     fn poll_eth(c: poll_eth::Context) {
-        static mut NOW: u32 = 0;
+        // Note: This also keeps the 64 bit uptime extension of the cycle counter current.
+        let now = SystemTimer::millis() as u32;
 
-        match c.resources.network.update(*NOW) {
-            NetworkState::SettingsChanged => c.spawn.settings_update().unwrap(),
+        match c.resources.network.update(now) {
+            NetworkState::SettingsChanged => {
+                // A pending update applies the latest settings as well.
+                c.spawn.settings_update().ok();
+            }
+            NetworkState::Command(command) => {
+                if c.spawn.command(command).is_err() {
+                    log::warn!("Command dropped: {:?}", command);
+                }
+            }
             NetworkState::Updated => {}
             NetworkState::NoChange => {}
         }
-        *NOW = *NOW + 1;
         c.schedule
             .poll_eth(c.scheduled + ETH_P_PERIOD.cycles())
             .unwrap();
     }
 
-    #[idle(resources=[adc], spawn=[process])]
+    #[idle(resources=[adc, telemetry, &self_test], spawn=[process, calibrated])]
     fn idle(mut c: idle::Context) -> ! {
+        // Without the ADC there are no samples to process.
+        if !c.resources.self_test.adc {
+            loop {
+                cortex_m::asm::nop();
+            }
+        }
+
         let mut adcdata1 = 0; // initialize to zero in case ch0 comes first
         loop {
-            let statreg = c.resources.adc.lock(|adc| adc.get_status_reg());
-            if statreg != 0xff {
-                let (adcdata, ch) = c.resources.adc.lock(|adc| adc.read_data());
+            // Status and data are read at once, so no other conversion can interfere. During a
+            // calibration, the ADC reports ready once it completed.
+            let (sample, calibrated) = c.resources.adc.lock(|adc| match adc.read_status() {
+                Ok(status) if status.ready && adc.is_calibrating() => {
+                    (None, adc.finish_calibration())
+                }
+                Ok(status) if status.ready => (Some((adc.read_data(), adc.crc_errors())), None),
+                _ => (None, None),
+            });
+            if let Some((ch, coefficients)) = calibrated {
+                // ADC ch1 is Thermostat ch0
+                if c.spawn.calibrated(1 - ch as usize, coefficients).is_err() {
+                    log::warn!("ADC calibration result dropped");
+                }
+            }
+            if let Some((data, crc_errors)) = sample {
+                c.resources
+                    .telemetry
+                    .lock(|tele| tele.adc_crc_errors = crc_errors);
+                // Corrupted data is discarded.
+                let (adcdata, status) = match data {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                };
+                // ADC ch2 and ch3 are the auxiliary channels. Ch3 is also used for diagnostics.
+                if status.channel >= 2 {
+                    let aux = status.channel as usize - 2;
+                    let diagnostic = c.resources.adc.lock(|adc| {
+                        if status.channel == DIAGNOSTIC_CHANNEL && adc.is_measuring() {
+                            adc.measured(adcdata, status);
+                            true
+                        } else {
+                            adc.aux_sampled(status.channel);
+                            false
+                        }
+                    });
+                    if diagnostic {
+                        continue;
+                    }
+                    if status.is_error() {
+                        log::warn!("ADC error on auxiliary channel {}: {:?}", aux, status);
+                    } else {
+                        c.resources.telemetry.lock(|tele| tele.aux[aux] = adcdata);
+                    }
+                    continue;
+                }
+                // ADC ch1 is Thermostat ch0
+                let ch = if status.channel == 0 { 1 } else { 0 };
+                if ch == 0 {
+                    c.resources.adc.lock(|adc| adc.sequence_completed());
+                }
+                if status.is_error() {
+                    // Discard the sample. The controller continues with the last valid one.
+                    log::warn!("ADC error on channel {}: {:?}", ch, status);
+                    c.resources.telemetry.lock(|tele| tele.adc_errors[ch] += 1);
+                    continue;
+                }
                 match ch {
-                    0 => {
+                    1 => {
                         adcdata1 = adcdata;
+                        c.resources.telemetry.lock(|tele| tele.samples[1] += 1);
                     }
                     _ => {
-                        // ADC ch1 is Thermostat ch0
                         let adcdata0 = adcdata;
-                        c.spawn.process([adcdata0, adcdata1]).unwrap();
+                        c.resources.telemetry.lock(|tele| tele.samples[0] += 1);
+                        if c.spawn.process([adcdata0, adcdata1]).is_err() {
+                            c.resources.telemetry.lock(|tele| tele.overruns += 1);
+                        }
                     }
                 }
             }
@@ -254,10 +742,12 @@ const APP: () = {
 
     #[task(priority = 1, resources = [network, telemetry, settings], schedule = [tele])]
     fn tele(c: tele::Context) {
-        c.resources
-            .network
-            .telemetry
-            .publish(&c.resources.telemetry.finalize());
+        let telemetry = c.resources.telemetry.finalize(
+            SystemTimer::millis(),
+            c.resources.network.status(),
+            &c.resources.settings.temperature_calibration,
+        );
+        c.resources.network.publish_telemetry(&telemetry);
 
         c.schedule
             .tele(
@@ -267,6 +757,74 @@ const APP: () = {
             .unwrap();
     }
 
+    // Periodically read back the ADC configuration and reinitialize the ADC when it diverged.
+    #[task(priority = 1, resources = [adc, telemetry], schedule = [check_adc])]
+    fn check_adc(c: check_adc::Context) {
+        c.resources.adc.check_config();
+        c.resources.telemetry.adc_reinits = c.resources.adc.reinits();
+
+        c.schedule
+            .check_adc(c.scheduled + ADC_CHECK_PERIOD.cycles())
+            .unwrap();
+    }
+
+    #[task(priority = 1, resources = [tec_monitor, dacs, telemetry, settings], schedule = [tec_readback])]
+    fn tec_readback(c: tec_readback::Context) {
+        for ch in 0..2 {
+            let commanded = if c.resources.dacs.is_enabled(ch as u8) {
+                // The drivers limit the current, e.g. of a DAC value set directly.
+                let limits = &c.resources.settings.pidsettings[ch];
+                Some(
+                    dac_to_i(c.resources.dacs.val[ch])
+                        .max(-limits.max_i_neg)
+                        .min(limits.max_i_pos),
+                )
+            } else {
+                None
+            };
+            let readback = c.resources.tec_monitor.measure(ch, commanded);
+            c.resources.telemetry.tec_current[ch] = readback.current;
+            c.resources.telemetry.tec_voltage[ch] = readback.voltage;
+            c.resources.telemetry.tec_mismatch[ch] = readback.mismatch;
+        }
+
+        c.schedule
+            .tec_readback(c.scheduled + TEC_READBACK_PERIOD.cycles())
+            .unwrap();
+    }
+
+    #[task(priority = 1, resources = [diagnostics, adc, tec_monitor, network], schedule = [diagnostics])]
+    fn diagnostics(c: diagnostics::Context) {
+        if let Some(diagnostics) = c
+            .resources
+            .diagnostics
+            .update(c.resources.adc, c.resources.tec_monitor)
+        {
+            c.resources.network.publish_diagnostics(&diagnostics);
+        }
+
+        c.schedule
+            .diagnostics(c.scheduled + DIAGNOSTICS_PERIOD.cycles())
+            .unwrap();
+    }
+
+    // Trigger the ADC conversions at the configured rate in the triggered ADC mode.
+    #[task(priority = 1, resources = [adc, settings], schedule = [trigger_adc])]
+    fn trigger_adc(c: trigger_adc::Context) {
+        let period = match c.resources.settings.adc_mode {
+            AdcMode::Continuous => ADC_MODE_POLL_PERIOD,
+            AdcMode::Triggered => {
+                c.resources.adc.trigger();
+                let rate = c.resources.settings.adc_rate.max(MIN_ADC_RATE);
+                (CYC_PER_S as f32 / rate) as u32
+            }
+        };
+
+        c.schedule
+            .trigger_adc(c.scheduled + period.cycles())
+            .unwrap();
+    }
+
     #[task(priority = 1, resources = [leds], schedule = [blink])]
     fn blink(c: blink::Context) {
         static mut LED_STATE: bool = false;
@@ -0,0 +1,582 @@
+///! mDNS/DNS-SD responder
+///!
+///! # Design
+///! The device publishes its host name `<hostname>.local` and a DNS-SD service instance
+///! `<hostname>._thermostat._tcp.local` via multicast DNS (RFC 6762, RFC 6763). The TXT record of
+///! the service carries the MQTT prefix, the MAC address and the firmware version, so host tools
+///! can discover thermostats and their topics without knowing their addresses.
+///!
+///! The interface joins the mDNS group 224.0.0.251 (see `setup`) and the responder listens on port
+///! 5353. Queries for the PTR, SRV, TXT or A records of the device (or for any of its records) are
+///! answered by multicast. Queries from other ports than 5353 are legacy unicast queries and are
+///! answered directly to the querier (RFC 6762 6.7). Known-answer suppression and response delays
+///! are not implemented, the answers are sent right away.
+///!
+///! In addition, the records are announced twice after an address was assigned (RFC 6762 8.3) and
+///! periodically afterwards, so caches pick up address changes without querying.
+use byteorder::{BigEndian, ByteOrder};
+use core::fmt::Write;
+use heapless::{String, Vec};
+use minimq::embedded_nal::{nb, IpAddr, Ipv4Addr, SocketAddr, UdpClientStack, UdpFullStack};
+use smoltcp_nal::smoltcp::wire::{EthernetAddress, Ipv4Address};
+
+use crate::network_users::NetworkReference;
+use crate::system_timer::SystemTimer;
+
+const MDNS_PORT: u16 = 5353;
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MAX_MESSAGE_SIZE: usize = 512;
+
+/// The DNS-SD service type advertised by the device.
+const SERVICE: &str = "_thermostat._tcp";
+
+/// TTL of records tied to the host (A, SRV) and of the other records (PTR, TXT) (RFC 6762 10).
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+/// Maximum TTL in answers to legacy unicast queries (RFC 6762 6.7).
+const LEGACY_TTL: u32 = 10;
+
+/// Maximum number of queries handled per update.
+const MAX_QUERIES_PER_UPDATE: usize = 4;
+
+/// Interval between the initial announcements and between the periodic ones afterwards.
+const INITIAL_INTERVAL_MS: u64 = 1_000;
+const ANNOUNCE_INTERVAL_MS: u64 = 60_000;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const CLASS_CACHE_FLUSH: u16 = 1 << 15;
+const CLASS_MASK: u16 = !(1 << 15); // Without the cache flush or unicast response bit
+const FLAGS_RESPONSE: u16 = 0x8400; // QR, AA
+const FLAGS_QR_OPCODE: u16 = 0xF800;
+
+/// Sets of the records of the device.
+const RECORD_PTR: u8 = 1 << 0;
+const RECORD_SRV: u8 = 1 << 1;
+const RECORD_TXT: u8 = 1 << 2;
+const RECORD_A: u8 = 1 << 3;
+const RECORDS_ALL: u8 = RECORD_PTR | RECORD_SRV | RECORD_TXT | RECORD_A;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MdnsError {
+    /// The response does not fit into a message.
+    TooLarge,
+    /// The network stack failed to send or receive.
+    Network,
+}
+
+/// A query to answer.
+struct Query {
+    /// The records asked for.
+    records: u8,
+    /// The querier if the query is a legacy unicast one.
+    legacy: Option<SocketAddr>,
+    /// The message ID of the query.
+    id: u16,
+    /// The number of questions of the query.
+    questions: u16,
+    /// The end of the question section of the query.
+    questions_end: usize,
+}
+
+type UdpSocket = <NetworkReference as UdpClientStack>::UdpSocket;
+
+pub struct Responder {
+    stack: NetworkReference,
+    socket: Option<UdpSocket>,
+    hostname: String<64>,
+    port: u16,
+    txt: Vec<u8, 256>,
+    ip: Ipv4Address,
+    announcements: u32,
+    next: u64,
+}
+
+impl Responder {
+    /// Construct a new responder.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the (shared) underlying network stack.
+    /// * `app` - The name of the application.
+    /// * `mac` - The MAC address of the device.
+    /// * `alias` - The device alias. The host name is derived from the MAC address if it is empty.
+    /// * `prefix` - The MQTT prefix of the device.
+    /// * `port` - The port of the advertised service. The service is not advertised if it is 0.
+    pub fn new(
+        stack: NetworkReference,
+        app: &str,
+        mac: EthernetAddress,
+        alias: &str,
+        prefix: &str,
+        port: u16,
+    ) -> Self {
+        let mut hostname: String<64> = String::new();
+        if alias.is_empty() {
+            let m = mac.as_bytes();
+            write!(
+                &mut hostname,
+                "{}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+                app, m[0], m[1], m[2], m[3], m[4], m[5]
+            )
+            .unwrap();
+        } else {
+            // Host names are restricted to letters, digits and hyphens.
+            for c in alias.chars() {
+                let c = if c.is_ascii_alphanumeric() { c } else { '-' };
+                hostname.push(c.to_ascii_lowercase()).ok();
+            }
+        }
+
+        let mut txt: Vec<u8, 256> = Vec::new();
+        let mut entry: String<128> = String::new();
+        write!(&mut entry, "prefix={}", prefix).ok();
+        push_txt(&mut txt, &entry);
+        entry.clear();
+        write!(&mut entry, "mac={}", mac).ok();
+        push_txt(&mut txt, &entry);
+        entry.clear();
+        write!(&mut entry, "version={}", env!("CARGO_PKG_VERSION")).ok();
+        push_txt(&mut txt, &entry);
+
+        log::info!("mDNS host name: {}.local", hostname);
+
+        Self {
+            stack,
+            socket: None,
+            hostname,
+            port,
+            txt,
+            ip: Ipv4Address::UNSPECIFIED,
+            announcements: 0,
+            next: 0,
+        }
+    }
+
+    /// Answer queries and send announcements when they are due.
+    ///
+    /// # Note
+    /// The announcements restart whenever the interface address changes, e.g. after a new DHCP
+    /// lease.
+    pub fn update(&mut self) {
+        let ip = self
+            .stack
+            .lock(|stack| stack.interface().ipv4_address())
+            .unwrap_or(Ipv4Address::UNSPECIFIED);
+
+        if ip != self.ip {
+            self.ip = ip;
+            self.announcements = 0;
+            self.next = SystemTimer::millis();
+        }
+
+        if ip.is_unspecified() {
+            return;
+        }
+
+        if let Err(error) = self.open() {
+            log::warn!("mDNS socket unavailable: {:?}", error);
+            return;
+        }
+
+        for _ in 0..MAX_QUERIES_PER_UPDATE {
+            match self.answer() {
+                Ok(()) => {}
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(error)) => log::warn!("mDNS answer failed: {:?}", error),
+            }
+        }
+
+        if SystemTimer::millis() < self.next {
+            return;
+        }
+
+        let destination = SocketAddr::new(IpAddr::V4(MDNS_GROUP), MDNS_PORT);
+        if let Err(error) = self.respond(self.records(), None, destination) {
+            log::warn!("mDNS announcement failed: {:?}", error);
+        }
+
+        self.announcements += 1;
+        self.next = SystemTimer::millis()
+            + if self.announcements < 2 {
+                INITIAL_INTERVAL_MS
+            } else {
+                ANNOUNCE_INTERVAL_MS
+            };
+    }
+
+    /// The set of records of the device. The service records are omitted if the service is
+    /// disabled (port 0).
+    fn records(&self) -> u8 {
+        if self.port == 0 {
+            RECORD_A
+        } else {
+            RECORDS_ALL
+        }
+    }
+
+    /// Open the socket on the mDNS port if it is not open yet.
+    fn open(&mut self) -> Result<(), MdnsError> {
+        if self.socket.is_some() {
+            return Ok(());
+        }
+        // Queries are received on and responses sent from the mDNS port (RFC 6762 11).
+        let mut socket = UdpClientStack::socket(&mut self.stack).map_err(|_| MdnsError::Network)?;
+        if UdpFullStack::bind(&mut self.stack, &mut socket, MDNS_PORT).is_err() {
+            UdpClientStack::close(&mut self.stack, socket).ok();
+            return Err(MdnsError::Network);
+        }
+        self.socket.replace(socket);
+        Ok(())
+    }
+
+    /// Receive a single message and answer it if it is a query for records of the device.
+    ///
+    /// # Returns
+    /// `WouldBlock` if no message was received.
+    fn answer(&mut self) -> nb::Result<(), MdnsError> {
+        // Note(unwrap): Only called once the socket is open.
+        let socket = self.socket.as_mut().unwrap();
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let (len, source) = UdpClientStack::receive(&mut self.stack, socket, &mut buf)
+            .map_err(|e| e.map(|_| MdnsError::Network))?;
+        let message = &buf[..len];
+
+        let query = match self.parse_query(message, source) {
+            Some(query) if query.records != 0 => query,
+            _ => return Ok(()),
+        };
+
+        match query.legacy {
+            Some(querier) => {
+                let question = (query.id, query.questions, &message[12..query.questions_end]);
+                self.respond(query.records, Some(question), querier)?
+            }
+            None => {
+                let destination = SocketAddr::new(IpAddr::V4(MDNS_GROUP), MDNS_PORT);
+                self.respond(query.records, None, destination)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse a query and collect the records of the device it asks for.
+    ///
+    /// # Returns
+    /// The query or `None` if the message is not a well formed standard query.
+    fn parse_query(&self, message: &[u8], source: SocketAddr) -> Option<Query> {
+        if message.len() < 12 || BigEndian::read_u16(&message[2..4]) & FLAGS_QR_OPCODE != 0 {
+            return None;
+        }
+        let questions = BigEndian::read_u16(&message[4..6]);
+
+        let mut host: String<128> = String::new();
+        write!(&mut host, "{}.local", self.hostname).ok()?;
+        let mut service: String<128> = String::new();
+        write!(&mut service, "{}.local", SERVICE).ok()?;
+        let mut instance: String<128> = String::new();
+        write!(&mut instance, "{}.{}.local", self.hostname, SERVICE).ok()?;
+
+        let mut records = 0;
+        let mut offset = 12;
+        for _ in 0..questions {
+            let mut name: String<256> = String::new();
+            offset = read_name(message, offset, &mut name)?;
+            let question = message.get(offset..offset + 4)?;
+            let qtype = BigEndian::read_u16(&question[0..2]);
+            let class = BigEndian::read_u16(&question[2..4]) & CLASS_MASK;
+            offset += 4;
+
+            if class != CLASS_IN && class != CLASS_ANY {
+                continue;
+            }
+            let wanted = |rtype| qtype == rtype || qtype == TYPE_ANY;
+            if name.eq_ignore_ascii_case(&service) && wanted(TYPE_PTR) {
+                records |= RECORD_PTR;
+            } else if name.eq_ignore_ascii_case(&instance) {
+                if wanted(TYPE_SRV) {
+                    records |= RECORD_SRV;
+                }
+                if wanted(TYPE_TXT) {
+                    records |= RECORD_TXT;
+                }
+            } else if name.eq_ignore_ascii_case(&host) && wanted(TYPE_A) {
+                records |= RECORD_A;
+            }
+        }
+
+        Some(Query {
+            records: records & self.records(),
+            legacy: Some(source).filter(|source| source.port() != MDNS_PORT),
+            id: BigEndian::read_u16(&message[0..2]),
+            questions,
+            questions_end: offset,
+        })
+    }
+
+    /// Send a response with records of the device.
+    ///
+    /// # Args
+    /// * `records` - The set of records to send.
+    /// * `question` - The ID, the number of questions and the question section of a legacy
+    ///   unicast query to answer. `None` for multicast responses.
+    /// * `destination` - The destination of the response.
+    fn respond(
+        &mut self,
+        records: u8,
+        question: Option<(u16, u16, &[u8])>,
+        destination: SocketAddr,
+    ) -> Result<(), MdnsError> {
+        let message = self
+            .encode(records, question)
+            .map_err(|_| MdnsError::TooLarge)?;
+        // Note(unwrap): Only called once the socket is open.
+        let socket = self.socket.as_mut().unwrap();
+        UdpFullStack::send_to(&mut self.stack, socket, destination, &message)
+            .map_err(|_| MdnsError::Network)
+    }
+
+    /// Encode a response with records of the device.
+    ///
+    /// # Args
+    /// * `records` - The set of records to include.
+    /// * `question` - The ID, the number of questions and the question section of a legacy
+    ///   unicast query to answer. `None` for multicast responses.
+    fn encode(
+        &self,
+        records: u8,
+        question: Option<(u16, u16, &[u8])>,
+    ) -> Result<Vec<u8, MAX_MESSAGE_SIZE>, ()> {
+        let mut message: Vec<u8, MAX_MESSAGE_SIZE> = Vec::new();
+        let mut header = [0u8; 12];
+        BigEndian::write_u16(&mut header[2..4], FLAGS_RESPONSE);
+        BigEndian::write_u16(&mut header[6..8], records.count_ones() as u16); // ANCOUNT
+        message.extend_from_slice(&header)?;
+
+        // Legacy unicast answers repeat the query ID and the questions, have no cache flush bit and
+        // a short TTL (RFC 6762 6.7). Compressed names in the questions remain valid, as they
+        // are at the same offset as in the query.
+        let (flush, host_ttl, other_ttl) = match question {
+            Some((id, count, questions)) => {
+                BigEndian::write_u16(&mut message[0..2], id);
+                BigEndian::write_u16(&mut message[4..6], count); // QDCOUNT
+                message.extend_from_slice(questions)?;
+                (0, LEGACY_TTL, LEGACY_TTL)
+            }
+            None => (CLASS_CACHE_FLUSH, HOST_TTL, OTHER_TTL),
+        };
+
+        let host = [self.hostname.as_str(), "local"];
+        let service = [SERVICE, "local"];
+        let instance = [self.hostname.as_str(), SERVICE, "local"];
+
+        let mut data: Vec<u8, 128> = Vec::new();
+        if records & RECORD_PTR != 0 {
+            // PTR: service type -> service instance
+            push_name(&mut data, &instance)?;
+            push_record(&mut message, &service, TYPE_PTR, CLASS_IN, other_ttl, &data)?;
+        }
+
+        if records & RECORD_SRV != 0 {
+            // SRV: service instance -> host and port
+            data.clear();
+            data.extend_from_slice(&[0, 0, 0, 0])?; // Priority, weight
+            data.extend_from_slice(&self.port.to_be_bytes())?;
+            push_name(&mut data, &host)?;
+            push_record(
+                &mut message,
+                &instance,
+                TYPE_SRV,
+                CLASS_IN | flush,
+                host_ttl,
+                &data,
+            )?;
+        }
+
+        if records & RECORD_TXT != 0 {
+            // TXT: service instance -> key/value pairs
+            push_record(
+                &mut message,
+                &instance,
+                TYPE_TXT,
+                CLASS_IN | flush,
+                other_ttl,
+                &self.txt,
+            )?;
+        }
+
+        if records & RECORD_A != 0 {
+            // A: host -> address
+            push_record(
+                &mut message,
+                &host,
+                TYPE_A,
+                CLASS_IN | flush,
+                host_ttl,
+                &self.ip.0,
+            )?;
+        }
+
+        Ok(message)
+    }
+}
+
+/// Read a (possibly compressed) name as dot separated labels.
+///
+/// # Returns
+/// The offset of the first byte after the name or `None` if it is malformed or too long.
+fn read_name<const N: usize>(
+    message: &[u8],
+    mut offset: usize,
+    name: &mut String<N>,
+) -> Option<usize> {
+    let mut end = None;
+    // Bound the number of compression pointers followed to reject loops.
+    for _ in 0..16 {
+        loop {
+            let len = *message.get(offset)? as usize;
+            if len == 0 {
+                return Some(end.unwrap_or(offset + 1));
+            }
+            if len & 0xC0 == 0xC0 {
+                let pointer = BigEndian::read_u16(message.get(offset..offset + 2)?) & 0x3FFF;
+                end.get_or_insert(offset + 2);
+                offset = pointer as usize;
+                break;
+            }
+            let label = core::str::from_utf8(message.get(offset + 1..offset + 1 + len)?).ok()?;
+            if !name.is_empty() {
+                name.push('.').ok()?;
+            }
+            name.push_str(label).ok()?;
+            offset += 1 + len;
+        }
+    }
+    None
+}
+
+/// Append a TXT record string. Strings that do not fit are dropped.
+fn push_txt(txt: &mut Vec<u8, 256>, entry: &str) {
+    if entry.len() <= 255 && txt.len() + 1 + entry.len() <= txt.capacity() {
+        txt.push(entry.len() as u8).unwrap();
+        txt.extend_from_slice(entry.as_bytes()).unwrap();
+    }
+}
+
+/// Append an uncompressed name. Each element may contain multiple dot separated labels.
+fn push_name<const N: usize>(buf: &mut Vec<u8, N>, name: &[&str]) -> Result<(), ()> {
+    for label in name.iter().flat_map(|part| part.split('.')) {
+        if label.is_empty() || label.len() > 63 {
+            return Err(());
+        }
+        buf.push(label.len() as u8).map_err(|_| ())?;
+        buf.extend_from_slice(label.as_bytes())?;
+    }
+    buf.push(0).map_err(|_| ())
+}
+
+/// Append a resource record.
+fn push_record<const N: usize>(
+    buf: &mut Vec<u8, N>,
+    name: &[&str],
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    data: &[u8],
+) -> Result<(), ()> {
+    push_name(buf, name)?;
+    let mut record = [0u8; 10];
+    BigEndian::write_u16(&mut record[0..2], rtype);
+    BigEndian::write_u16(&mut record[2..4], class);
+    BigEndian::write_u32(&mut record[4..8], ttl);
+    BigEndian::write_u16(&mut record[8..10], data.len() as u16);
+    buf.extend_from_slice(&record)?;
+    buf.extend_from_slice(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_round_trip() {
+        let mut buf: Vec<u8, 64> = Vec::new();
+        push_name(&mut buf, &["thermostat-0011", SERVICE, "local"]).unwrap();
+        assert_eq!(
+            &buf[..],
+            b"\x0fthermostat-0011\x0b_thermostat\x04_tcp\x05local\x00"
+        );
+
+        let mut name: String<64> = String::new();
+        assert_eq!(read_name(&buf, 0, &mut name), Some(buf.len()));
+        assert_eq!(name, "thermostat-0011._thermostat._tcp.local");
+
+        assert!(push_name(&mut buf, &["a..b"]).is_err());
+    }
+
+    #[test]
+    fn compressed_name() {
+        let mut message: Vec<u8, 64> = Vec::new();
+        push_name(&mut message, &[SERVICE, "local"]).unwrap();
+        // The instance name with a pointer to the service type.
+        let instance = message.len();
+        message
+            .extend_from_slice(b"\x0fthermostat-0011\xC0\x00")
+            .unwrap();
+
+        let mut name: String<64> = String::new();
+        assert_eq!(
+            read_name(&message, instance, &mut name),
+            Some(message.len())
+        );
+        assert_eq!(name, "thermostat-0011._thermostat._tcp.local");
+    }
+
+    #[test]
+    fn malformed_names() {
+        let mut name: String<64> = String::new();
+        // A pointer to itself.
+        assert_eq!(read_name(&[0xC0, 0x00], 0, &mut name), None);
+        // A label beyond the end of the message.
+        assert_eq!(read_name(b"\x05local", 0, &mut name), None);
+        // A name longer than the buffer.
+        let mut name: String<4> = String::new();
+        assert_eq!(read_name(b"\x05local\x00", 0, &mut name), None);
+    }
+
+    #[test]
+    fn record() {
+        let mut buf: Vec<u8, 64> = Vec::new();
+        let ip = [192, 168, 1, 10];
+        push_record(
+            &mut buf,
+            &["thermostat", "local"],
+            TYPE_A,
+            CLASS_IN | CLASS_CACHE_FLUSH,
+            HOST_TTL,
+            &ip,
+        )
+        .unwrap();
+
+        let mut name: String<64> = String::new();
+        let offset = read_name(&buf, 0, &mut name).unwrap();
+        assert_eq!(name, "thermostat.local");
+        assert_eq!(
+            &buf[offset..],
+            &[0x00, 0x01, 0x80, 0x01, 0x00, 0x00, 0x00, 120, 0x00, 0x04, 192, 168, 1, 10]
+        );
+    }
+
+    #[test]
+    fn txt() {
+        let mut txt: Vec<u8, 256> = Vec::new();
+        push_txt(&mut txt, "prefix=dt/sinara/thermostat");
+        push_txt(&mut txt, core::str::from_utf8(&[b'a'; 256]).unwrap());
+        assert_eq!(txt[0] as usize, "prefix=dt/sinara/thermostat".len());
+        assert_eq!(txt.len(), 1 + txt[0] as usize);
+    }
+}
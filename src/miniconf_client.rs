@@ -0,0 +1,139 @@
+///! Thermostat settings and command MQTT client
+///!
+///! # Design
+///! The client subscribes to `<prefix>/settings/#` and applies every received message to the
+///! settings tree using the miniconf path of the topic, e.g. a message on
+///! `<prefix>/settings/pidsettings/0/target` updates the target temperature of channel 0. Unlike
+///! the client shipped with miniconf, it is constructed with the initial (e.g. persisted) settings
+///! and permits local modification of the active settings.
+///!
+///! Additionally, the client subscribes to `<prefix>/command/#` to receive device commands.
+use heapless::String;
+use miniconf::Miniconf;
+use minimq::QoS;
+
+use crate::broker::BrokerStack;
+use crate::commands::Command;
+use crate::system_timer::SystemTimer;
+use minimq::embedded_nal::{IpAddr, Ipv4Addr};
+
+/// The size of the MQTT message buffer of the settings client.
+const MQTT_BUFFER_SIZE: usize = 512;
+
+pub struct MiniconfClient<S: Miniconf> {
+    mqtt: minimq::Minimq<BrokerStack, SystemTimer, MQTT_BUFFER_SIZE, 1>,
+    settings: S,
+    settings_prefix: String<128>,
+    command_prefix: String<128>,
+    subscribed: bool,
+    command: Option<Command>,
+}
+
+impl<S: Miniconf> MiniconfClient<S> {
+    /// Construct a new settings client.
+    ///
+    /// # Args
+    /// * `stack` - A proxy of the (shared) underlying network stack connecting to the broker.
+    /// * `client_id` - The MQTT client ID of the settings client.
+    /// * `prefix` - The device prefix to use for MQTT settings and commands.
+    /// * `settings` - The initial settings.
+    ///
+    /// # Returns
+    /// A new settings client.
+    pub fn new(stack: BrokerStack, client_id: &str, prefix: &str, settings: S) -> Self {
+        let mqtt = minimq::Minimq::new(
+            // The broker address is substituted by the `BrokerStack`.
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            client_id,
+            stack,
+            SystemTimer,
+        )
+        .unwrap();
+
+        let mut settings_prefix: String<128> = String::from(prefix);
+        settings_prefix.push_str("/settings").unwrap();
+
+        let mut command_prefix: String<128> = String::from(prefix);
+        command_prefix.push_str("/command").unwrap();
+
+        Self {
+            mqtt,
+            settings,
+            settings_prefix,
+            command_prefix,
+            subscribed: false,
+            command: None,
+        }
+    }
+
+    /// Update the settings client.
+    ///
+    /// # Note
+    /// This processes incoming settings and commands and must be called regularly.
+    ///
+    /// # Returns
+    /// True if the settings were modified.
+    pub fn update(&mut self) -> Result<bool, minimq::Error<smoltcp_nal::NetworkError>> {
+        if !self.mqtt.client.is_connected() {
+            self.subscribed = false;
+        } else if !self.subscribed {
+            let mut settings_topic: String<130> = String::from(self.settings_prefix.as_str());
+            settings_topic.push_str("/#").unwrap();
+            let mut command_topic: String<130> = String::from(self.command_prefix.as_str());
+            command_topic.push_str("/#").unwrap();
+
+            self.mqtt.client.subscribe(&settings_topic, &[])?;
+            self.mqtt.client.subscribe(&command_topic, &[])?;
+            self.subscribed = true;
+        }
+
+        let settings = &mut self.settings;
+        let settings_prefix = &self.settings_prefix;
+        let command_prefix = &self.command_prefix;
+        let pending = &mut self.command;
+        let mut updated = false;
+
+        self.mqtt.poll(|_client, topic, message, _properties| {
+            if let Some(path) = strip_topic(topic, settings_prefix) {
+                match settings.string_set(path.split('/').peekable(), message) {
+                    Ok(()) => updated = true,
+                    Err(error) => log::warn!("Settings update of {} failed: {:?}", path, error),
+                }
+            } else if let Some(name) = strip_topic(topic, command_prefix) {
+                match Command::parse(name, message) {
+                    Some(command) => {
+                        if pending.replace(command).is_some() {
+                            log::warn!("Dropping unhandled command");
+                        }
+                    }
+                    None => log::warn!("Unknown command: {}", name),
+                }
+            }
+        })?;
+
+        Ok(updated)
+    }
+
+    /// Get the active settings.
+    pub fn settings(&self) -> &S {
+        &self.settings
+    }
+
+    /// Get mutable access to the active settings.
+    ///
+    /// # Note
+    /// Modifications are not applied until the settings are explicitly (re-)applied.
+    pub fn settings_mut(&mut self) -> &mut S {
+        &mut self.settings
+    }
+
+    /// Take the last received command, if any.
+    pub fn take_command(&mut self) -> Option<Command> {
+        self.command.take()
+    }
+}
+
+/// Strip a prefix and the separating `/` from a topic.
+fn strip_topic<'a>(topic: &'a str, prefix: &str) -> Option<&'a str> {
+    topic.strip_prefix(prefix)?.strip_prefix('/')
+}
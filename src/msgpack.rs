@@ -0,0 +1,539 @@
+///! MessagePack serialization
+///!
+///! # Design
+///! A minimal `no_std` serde serializer writing MessagePack into a fixed buffer, as used for the
+///! telemetry payloads. Structs are encoded as maps with named fields, like the JSON and CBOR
+///! encodings, and enum variants as their name. Values are always encoded in the smallest
+///! representation.
+///!
+///! MessagePack prefixes arrays and maps with their length, so sequences and maps of unknown
+///! length can not be serialized.
+use core::fmt::{self, Write};
+use serde::ser::{self, Serialize};
+
+/// Errors that may occur during serialization.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The buffer is too small for the serialized value.
+    BufferFull,
+    /// A sequence or map of unknown length can not be encoded.
+    UnknownLength,
+    /// The value reported an error during serialization.
+    Custom,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Error::BufferFull => "buffer full",
+            Error::UnknownLength => "unknown length",
+            Error::Custom => "serialization error",
+        };
+        f.write_str(message)
+    }
+}
+
+impl ser::StdError for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        Error::Custom
+    }
+}
+
+/// Serialize a value as MessagePack into a buffer.
+///
+/// # Args
+/// * `value` - The value to serialize.
+/// * `buf` - The buffer to serialize into.
+///
+/// # Returns
+/// The number of bytes written into `buf`.
+pub fn to_slice<T: Serialize + ?Sized>(value: &T, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut serializer = Serializer { buf, len: 0 };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.len)
+}
+
+pub struct Serializer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+/// Counts the bytes of formatted output.
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+impl<'a> Write for Serializer<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl<'a> Serializer<'a> {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.len + data.len();
+        if end > self.buf.len() {
+            return Err(Error::BufferFull);
+        }
+        self.buf[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn write_uint(&mut self, v: u64) -> Result<(), Error> {
+        if v < 0x80 {
+            self.write(&[v as u8])
+        } else if v <= u8::MAX as u64 {
+            self.write(&[0xcc, v as u8])
+        } else if v <= u16::MAX as u64 {
+            self.write(&[0xcd])?;
+            self.write(&(v as u16).to_be_bytes())
+        } else if v <= u32::MAX as u64 {
+            self.write(&[0xce])?;
+            self.write(&(v as u32).to_be_bytes())
+        } else {
+            self.write(&[0xcf])?;
+            self.write(&v.to_be_bytes())
+        }
+    }
+
+    fn write_int(&mut self, v: i64) -> Result<(), Error> {
+        if v >= 0 {
+            self.write_uint(v as u64)
+        } else if v >= -32 {
+            self.write(&[v as u8])
+        } else if v >= i8::MIN as i64 {
+            self.write(&[0xd0, v as u8])
+        } else if v >= i16::MIN as i64 {
+            self.write(&[0xd1])?;
+            self.write(&(v as i16).to_be_bytes())
+        } else if v >= i32::MIN as i64 {
+            self.write(&[0xd2])?;
+            self.write(&(v as i32).to_be_bytes())
+        } else {
+            self.write(&[0xd3])?;
+            self.write(&v.to_be_bytes())
+        }
+    }
+
+    /// Write the header of a value with a length, e.g. a string or an array.
+    ///
+    /// # Args
+    /// * `len` - The length of the value.
+    /// * `fix` - The fix format marker and the maximum length it holds.
+    /// * `markers` - The markers of the 8, 16 and 32 bit length formats. `None` if there is no 8
+    ///   bit format.
+    fn write_header(
+        &mut self,
+        len: usize,
+        fix: (u8, usize),
+        markers: (Option<u8>, u8, u8),
+    ) -> Result<(), Error> {
+        match markers.0 {
+            _ if len <= fix.1 => self.write(&[fix.0 | len as u8]),
+            Some(marker) if len <= u8::MAX as usize => self.write(&[marker, len as u8]),
+            _ if len <= u16::MAX as usize => {
+                self.write(&[markers.1])?;
+                self.write(&(len as u16).to_be_bytes())
+            }
+            _ => {
+                self.write(&[markers.2])?;
+                self.write(&(len as u32).to_be_bytes())
+            }
+        }
+    }
+
+    fn write_str_header(&mut self, len: usize) -> Result<(), Error> {
+        self.write_header(len, (0xa0, 31), (Some(0xd9), 0xda, 0xdb))
+    }
+
+    fn write_array_header(&mut self, len: usize) -> Result<(), Error> {
+        self.write_header(len, (0x90, 15), (None, 0xdc, 0xdd))
+    }
+
+    fn write_map_header(&mut self, len: usize) -> Result<(), Error> {
+        self.write_header(len, (0x80, 15), (None, 0xde, 0xdf))
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.write(&[if v { 0xc3 } else { 0xc2 }])
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.write_int(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.write_int(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.write_int(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.write_int(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.write_uint(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.write_uint(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.write_uint(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.write_uint(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.write(&[0xca])?;
+        self.write(&v.to_be_bytes())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.write(&[0xcb])?;
+        self.write(&v.to_be_bytes())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.write_str_header(v.len())?;
+        self.write(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        // There is no fix format for binary data.
+        if v.len() <= u8::MAX as usize {
+            self.write(&[0xc4, v.len() as u8])?;
+        } else {
+            self.write_header(v.len(), (0, 0), (None, 0xc5, 0xc6))?;
+        }
+        self.write(v)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.write(&[0xc0])
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.write_map_header(1)?;
+        self.serialize_str(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, Error> {
+        self.write_array_header(len.ok_or(Error::UnknownLength)?)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self, Error> {
+        self.write_array_header(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Self, Error> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, Error> {
+        self.write_map_header(1)?;
+        self.serialize_str(variant)?;
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, Error> {
+        self.write_map_header(len.ok_or(Error::UnknownLength)?)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self, Error> {
+        self.write_map_header(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, Error> {
+        self.write_map_header(1)?;
+        self.serialize_str(variant)?;
+        self.serialize_struct(variant, len)
+    }
+
+    fn collect_str<T: fmt::Display + ?Sized>(self, value: &T) -> Result<(), Error> {
+        // The length is required ahead of the string.
+        let mut counter = Counter(0);
+        write!(&mut counter, "{}", value).map_err(|_| Error::Custom)?;
+        self.write_str_header(counter.0)?;
+        write!(self, "{}", value).map_err(|_| Error::BufferFull)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl<'a, 'b> ser::SerializeSeq for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeTuple for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleStruct for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleVariant for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeMap for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeStruct for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::Serializer::serialize_str(&mut **self, key)?;
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeStructVariant for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::Serializer::serialize_str(&mut **self, key)?;
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+        let mut buf = [0u8; 128];
+        let len = to_slice(value, &mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(encode(&5u8), [0x05]);
+        assert_eq!(encode(&200u32), [0xcc, 0xc8]);
+        assert_eq!(encode(&300u32), [0xcd, 0x01, 0x2c]);
+        assert_eq!(encode(&70000u32), [0xce, 0x00, 0x01, 0x11, 0x70]);
+        assert_eq!(
+            encode(&(1u64 << 32)),
+            [0xcf, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(encode(&7i32), [0x07]);
+        assert_eq!(encode(&-1i32), [0xff]);
+        assert_eq!(encode(&-32i32), [0xe0]);
+        assert_eq!(encode(&-33i32), [0xd0, 0xdf]);
+        assert_eq!(encode(&-200i32), [0xd1, 0xff, 0x38]);
+        assert_eq!(encode(&-40000i32), [0xd2, 0xff, 0xff, 0x63, 0xc0]);
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(encode(&1.5f32), [0xca, 0x3f, 0xc0, 0x00, 0x00]);
+        assert_eq!(
+            encode(&1.5f64),
+            [0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(encode("abc"), [0xa3, b'a', b'b', b'c']);
+        let long = "x".repeat(32);
+        assert_eq!(encode(long.as_str())[..2], [0xd9, 32]);
+        assert_eq!(encode(&'x'), [0xa1, b'x']);
+    }
+
+    #[test]
+    fn other_values() {
+        assert_eq!(encode(&true), [0xc3]);
+        assert_eq!(encode(&false), [0xc2]);
+        assert_eq!(encode(&None::<u8>), [0xc0]);
+        assert_eq!(encode(&Some(1u8)), [0x01]);
+        assert_eq!(encode(&[1u8, 2]), [0x92, 0x01, 0x02]);
+    }
+
+    #[derive(Serialize)]
+    enum Mode {
+        Continuous,
+    }
+
+    #[derive(Serialize)]
+    struct Message {
+        a: u32,
+        b: [bool; 1],
+        mode: Mode,
+    }
+
+    #[test]
+    fn structs() {
+        let message = Message {
+            a: 1,
+            b: [true],
+            mode: Mode::Continuous,
+        };
+        let mut expected = vec![0x83, 0xa1, b'a', 0x01, 0xa1, b'b', 0x91, 0xc3];
+        expected.extend_from_slice(&[0xa4, b'm', b'o', b'd', b'e', 0xaa]);
+        expected.extend_from_slice(b"Continuous");
+        assert_eq!(encode(&message), expected);
+    }
+
+    #[test]
+    fn buffer_full() {
+        let mut buf = [0u8; 3];
+        assert_eq!(to_slice("abc", &mut buf), Err(Error::BufferFull));
+        assert_eq!(to_slice(&300u32, &mut buf), Ok(3));
+    }
+}
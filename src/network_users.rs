@@ -3,41 +3,210 @@
 ///!
 ///! # Design
 ///! The network architecture supports numerous layers to permit transmission of
-///! telemetry (via MQTT), configuration of run-time settings (via MQTT + Miniconf).
+///! telemetry (via MQTT), configuration of run-time settings (via MQTT + Miniconf) and
+///! publication of the device identity, the self-test report, Home Assistant discovery configs and
+///! the active settings as retained MQTT messages.
 ///  This module encompasses the main processing routines
 ///! related to networking operations.
 pub use heapless;
 pub use miniconf;
 pub use serde;
 
+use crate::broker::{Broker, BrokerStack};
+use crate::commands::Command;
+use crate::discovery::DiscoveryPublisher;
+use crate::http_server::HttpServer;
+use crate::line_server::LineServer;
+use crate::mdns::Responder;
+use crate::miniconf_client::MiniconfClient;
+use crate::self_test::SelfTestReport;
+use crate::settings_tree::{self, Validate};
 use crate::setup::NetworkStack;
 use crate::shared::NetworkManager;
+use crate::system_timer::SystemTimer;
 use crate::telemetry::TelemetryClient;
-use minimq::embedded_nal::IpAddr;
+use crate::NetworkSettings;
+use minimq::embedded_nal::Ipv4Addr;
+use smoltcp_nal::smoltcp::wire::{IpAddress, IpCidr, Ipv4Address};
 
 use core::fmt::Write;
-use heapless::String;
+use heapless::{String, Vec};
 use miniconf::Miniconf;
 use serde::Serialize;
 
 pub type NetworkReference = crate::shared::NetworkStackProxy<'static, NetworkStack>;
 
+/// Time after boot within which the broker must be reached before the device falls back to the
+/// default network addressing.
+const NETWORK_FALLBACK_TIMEOUT_MS: u64 = 300_000;
+
+/// The maximum size of the JSON telemetry served by the TCP servers.
+const MAX_TELEMETRY_SIZE: usize = 768;
+
+/// Status of the network users as reported in telemetry.
+#[derive(Copy, Clone, Default, Serialize)]
+pub struct NetworkStatus {
+    /// Indicates that the telemetry client is connected to the broker.
+    pub connected: bool,
+    /// Number of times a lost broker connection was re-established.
+    pub reconnects: u32,
+    /// Number of failed broker connection attempts.
+    pub failures: u32,
+    /// The active broker. 0 is the primary, 1 the secondary broker.
+    pub broker: u8,
+    /// The IPv4 address of the interface (static or leased via DHCP). All zero if unassigned.
+    pub ip: [u8; 4],
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum NetworkState {
     SettingsChanged,
+    Command(Command),
     Updated,
     NoChange,
 }
 
-pub struct NetworkUsers<S: Default + Miniconf, T: Serialize> {
-    pub miniconf: miniconf::MqttClient<S, NetworkReference>,
+pub struct NetworkUsers<
+    S: Default + Miniconf + Serialize + Copy + PartialEq + Validate,
+    T: Serialize,
+> {
+    pub miniconf: MiniconfClient<S>,
     stackref: NetworkReference,
     pub telemetry: TelemetryClient<T>,
+    settings_publisher: SettingsPublisher<S>,
+    identity_publisher: RetainedPublisher,
+    self_test_publisher: RetainedPublisher,
+    discovery_publisher: DiscoveryPublisher,
+    mdns: Responder,
+    line_server: LineServer,
+    http_server: HttpServer,
+    /// The most recent telemetry as JSON for the TCP servers.
+    telemetry_json: Vec<u8, MAX_TELEMETRY_SIZE>,
+    broker: Broker,
+    fallback_deadline: Option<u64>,
+}
+
+/// The topic below the prefix under which the active settings are published.
+pub const ACTIVE_SETTINGS_TOPIC: &str = "settings_active";
+
+/// Publishes the active settings tree as retained messages under
+/// `<prefix>/settings_active/<path>`.
+///
+/// # Note
+/// The settings are published one leaf per update to avoid overflowing the socket buffers. They
+/// are published in a separate subtree from the `<prefix>/settings/<path>` topics miniconf
+/// subscribes to. Otherwise the broker would replay the retained values on every connection and
+/// override the settings loaded from flash.
+struct SettingsPublisher<S> {
+    topic: String<128>,
+    published: Option<S>,
+    next: Option<usize>,
+}
+
+impl<S> SettingsPublisher<S>
+where
+    S: Serialize + Copy + PartialEq,
+{
+    fn new(prefix: &str) -> Self {
+        let mut topic: String<128> = String::from(prefix);
+        write!(&mut topic, "/{}", ACTIVE_SETTINGS_TOPIC).unwrap();
+
+        Self {
+            topic,
+            published: None,
+            next: None,
+        }
+    }
+
+    /// Request a publication of the settings.
+    ///
+    /// # Args
+    /// * `settings` - The active settings.
+    /// * `force` - Publish even if the settings are identical to the last publication.
+    fn request(&mut self, settings: &S, force: bool) {
+        if force || self.published != Some(*settings) {
+            self.published = Some(*settings);
+            self.next = Some(0);
+        }
+    }
+
+    /// Publish the next pending settings leaf, if any.
+    fn update<T: Serialize>(&mut self, client: &mut TelemetryClient<T>) {
+        let (settings, index) = match (self.published.as_ref(), self.next) {
+            (Some(settings), Some(index)) if client.is_connected() => (settings, index),
+            _ => return,
+        };
+
+        let mut path: String<{ settings_tree::MAX_PATH_LENGTH }> = String::new();
+        let mut value: Vec<u8, { settings_tree::MAX_VALUE_SIZE }> = Vec::new();
+        match settings_tree::nth(settings, index, &mut path, &mut value) {
+            Ok(true) => {
+                let mut topic: String<192> = String::new();
+                write!(&mut topic, "{}/{}", self.topic, path).unwrap();
+
+                // A failed publication is retried during the next update.
+                if client.publish_retained(&topic, &value).is_ok() {
+                    self.next = Some(index + 1);
+                }
+            }
+            Ok(false) => {
+                log::info!("Published {} settings", index);
+                self.next = None;
+            }
+            Err(error) => {
+                log::warn!("Settings publication failed: {:?}", error);
+                self.next = None;
+            }
+        }
+    }
+}
+
+/// The identity of a device as published on `<prefix>/identity`.
+#[derive(Serialize)]
+struct Identity<'a> {
+    alias: &'a str,
+    mac: &'a str,
+    firmware: &'a str,
+    hardware: &'a str,
+}
+
+/// Publishes a message fixed at boot, e.g. the device identity, as a retained message on
+/// `<prefix>/<name>` after every (re-)connection.
+struct RetainedPublisher {
+    topic: String<128>,
+    payload: Vec<u8, 512>,
+    pending: bool,
+}
+
+impl RetainedPublisher {
+    fn new<M: Serialize>(prefix: &str, name: &str, message: &M) -> Self {
+        let mut topic: String<128> = String::from(prefix);
+        write!(&mut topic, "/{}", name).unwrap();
+
+        // Note(unwrap): The messages are bounded in size, so they always fit.
+        let mut buf = [0u8; 512];
+        let len = serde_json_core::to_slice(message, &mut buf).unwrap();
+        let payload = Vec::from_slice(&buf[..len]).unwrap();
+
+        Self {
+            topic,
+            payload,
+            pending: false,
+        }
+    }
+
+    /// Publish the message, if a publication is pending.
+    fn update<T: Serialize>(&mut self, client: &mut TelemetryClient<T>) {
+        // A failed publication is retried during the next update.
+        if self.pending && client.is_connected() {
+            self.pending = client.publish_retained(&self.topic, &self.payload).is_err();
+        }
+    }
 }
 
 impl<S, T> NetworkUsers<S, T>
 where
-    S: Default + Miniconf,
+    S: Default + Miniconf + Serialize + Copy + PartialEq + Validate,
     T: Serialize,
 {
     /// Construct default network users.
@@ -48,7 +217,11 @@ where
     /// * `cycle_counter` - The clock used for measuring time in the network.
     /// * `app` - The name of the application.
     /// * `mac` - The MAC address of the network.
-    /// * `broker` - The IP address of the MQTT broker to use.
+    /// * `network` - The network configuration in use.
+    /// * `settings` - The initial settings.
+    /// * `self_test` - The report of the boot self-test.
+    /// * `fallback` - Fall back to the default network addressing if the broker can not be reached
+    ///   after boot.
     ///
     /// # Returns
     /// A new struct of network users.
@@ -56,34 +229,91 @@ where
         stack: NetworkStack,
         app: &str,
         mac: smoltcp_nal::smoltcp::wire::EthernetAddress,
-        broker: IpAddr,
+        network: &NetworkSettings,
+        settings: S,
+        self_test: &SelfTestReport,
+        fallback: bool,
     ) -> Self {
         let stack_manager =
             cortex_m::singleton!(: NetworkManager = NetworkManager::new(stack)).unwrap();
 
-        let prefix = get_device_prefix(app, mac);
+        let prefix = get_device_prefix(network.prefix.as_str(), app, mac, network.alias.as_str())
+            .unwrap_or_else(|| {
+                log::warn!(
+                    "Invalid prefix template {}, using the default",
+                    network.prefix
+                );
+                get_device_prefix(DEFAULT_PREFIX_TEMPLATE, app, mac, "").unwrap()
+            });
+        log::info!("MQTT prefix: {}", prefix);
 
-        let settings = miniconf::MqttClient::new(
-            stack_manager.acquire_stack(),
+        let settings = MiniconfClient::new(
+            BrokerStack::new(stack_manager.acquire_stack()),
             &get_client_id(app, "settings", mac),
             &prefix,
-            broker,
-        )
-        .unwrap();
+            settings,
+        );
 
         let telemetry = TelemetryClient::new(
-            stack_manager.acquire_stack(),
+            BrokerStack::new(stack_manager.acquire_stack()),
             &get_client_id(app, "tlm", mac),
             &prefix,
-            broker,
+        );
+
+        // The advertised service is the line based command interface.
+        let mdns = Responder::new(
+            stack_manager.acquire_stack(),
+            app,
+            mac,
+            network.alias.as_str(),
+            &prefix,
+            network.tcp_port,
+        );
+
+        let line_server = LineServer::new(stack_manager.acquire_stack(), network.tcp_port);
+        let http_server = HttpServer::new(stack_manager.acquire_stack(), network.http_port);
+
+        // Without a configured server, the router is used. It is only known at resolution time
+        // with DHCP.
+        let dns_server = Some(network.dns)
+            .filter(|dns| *dns != [0; 4])
+            .map(Ipv4Addr::from);
+        let broker = Broker::new(
+            stack_manager.acquire_stack(),
+            network.broker,
+            network.secondary_broker,
+            dns_server,
         );
 
         let stackref = stack_manager.acquire_stack();
 
+        let mut mac_string: String<17> = String::new();
+        write!(&mut mac_string, "{}", mac).unwrap();
+        let identity = Identity {
+            alias: network.alias.as_str(),
+            mac: &mac_string,
+            firmware: env!("CARGO_PKG_VERSION"),
+            hardware: crate::HARDWARE_REVISION,
+        };
+
         NetworkUsers {
             miniconf: settings,
             stackref,
             telemetry,
+            settings_publisher: SettingsPublisher::new(&prefix),
+            identity_publisher: RetainedPublisher::new(&prefix, "identity", &identity),
+            self_test_publisher: RetainedPublisher::new(&prefix, "self_test", self_test),
+            discovery_publisher: DiscoveryPublisher::new(&prefix, app, mac, network.alias.as_str()),
+            mdns,
+            line_server,
+            http_server,
+            telemetry_json: Vec::new(),
+            broker,
+            fallback_deadline: if fallback {
+                Some(NETWORK_FALLBACK_TIMEOUT_MS)
+            } else {
+                None
+            },
         }
     }
 
@@ -93,7 +323,22 @@ where
     /// An indication if any of the network users indicated a state change.
     pub fn update(&mut self, now: u32) -> NetworkState {
         // Update the MQTT clients.
-        self.telemetry.update();
+        if self.telemetry.update() {
+            // Make the device and its active settings discoverable after every (re-)connection.
+            self.identity_publisher.pending = true;
+            self.self_test_publisher.pending = true;
+            self.discovery_publisher.request();
+            self.settings_publisher
+                .request(self.miniconf.settings(), true);
+        }
+
+        if self.telemetry.is_connected() {
+            self.fallback_deadline = None;
+        } else if let Some(deadline) = self.fallback_deadline {
+            if SystemTimer::millis() > deadline {
+                self.fall_back();
+            }
+        }
 
         // Poll for incoming data.
         let poll_result = match self.stackref.lock(|stack| stack.poll(now)) {
@@ -102,9 +347,123 @@ where
             Err(_) => NetworkState::Updated,
         };
 
-        match self.miniconf.update() {
-            Ok(true) => NetworkState::SettingsChanged,
-            _ => poll_result,
+        self.mdns.update();
+
+        // Manage the broker address and connection attempts while the clients can not connect.
+        self.broker.update(self.telemetry.is_connected());
+
+        // The servers are only served if there is no pending state change, so none is lost.
+        let updated = match self.miniconf.update() {
+            Ok(updated) => updated,
+            Err(minimq::Error::Network(smoltcp_nal::NetworkError::NoIpAddress)) => false,
+            Err(error) => {
+                log::info!("Unexpected error: {:?}", error);
+                false
+            }
+        };
+
+        let state = if updated {
+            NetworkState::SettingsChanged
+        } else {
+            match self.miniconf.take_command() {
+                Some(command) => NetworkState::Command(command),
+                None => {
+                    let settings = self.miniconf.settings_mut();
+                    match self.line_server.update(settings, &self.telemetry_json) {
+                        Some(state) => state,
+                        None => self
+                            .http_server
+                            .update(settings, &self.telemetry_json)
+                            .unwrap_or(poll_result),
+                    }
+                }
+            }
+        };
+
+        self.identity_publisher.update(&mut self.telemetry);
+        self.self_test_publisher.update(&mut self.telemetry);
+        self.discovery_publisher.update(&mut self.telemetry);
+        self.settings_publisher.update(&mut self.telemetry);
+
+        state
+    }
+
+    /// Check that the responses to commands were handed to the network stack.
+    pub fn is_flushed(&self) -> bool {
+        self.line_server.is_flushed()
+    }
+
+    /// Reconfigure the interface and the brokers to the default network addressing.
+    ///
+    /// # Note
+    /// The device keeps running, so the TECs stay under control. With DHCP, a lease acquired
+    /// later replaces the default address again.
+    fn fall_back(&mut self) {
+        log::warn!("Broker unreachable, falling back to the default network addressing");
+        self.fallback_deadline = None;
+
+        let default = NetworkSettings::default();
+        self.stackref.lock(|stack| {
+            let interface = stack.interface_mut();
+            interface.update_ip_addrs(|addrs| {
+                addrs[0] = IpCidr::new(
+                    IpAddress::from(Ipv4Address::from_bytes(&default.ip)),
+                    // Note(unwrap): The default netmask is contiguous.
+                    default.prefix_len().unwrap(),
+                );
+            });
+            interface
+                .routes_mut()
+                .add_default_ipv4_route(Ipv4Address::from_bytes(&default.gateway))
+                .ok();
+        });
+        self.broker
+            .set_hosts(default.broker, default.secondary_broker);
+    }
+
+    /// Publish the active settings after they were validated and applied.
+    pub fn publish_settings(&mut self) {
+        self.settings_publisher
+            .request(self.miniconf.settings(), false);
+    }
+
+    /// Publish telemetry over MQTT and provide it to the TCP servers.
+    ///
+    /// # Args
+    /// * `telemetry` - The telemetry to report.
+    pub fn publish_telemetry(&mut self, telemetry: &T) {
+        self.telemetry.publish(telemetry);
+
+        let mut buf = [0u8; MAX_TELEMETRY_SIZE];
+        self.telemetry_json.clear();
+        match serde_json_core::to_slice(telemetry, &mut buf) {
+            // Note(unwrap): The buffer is as large as the cache.
+            Ok(len) => self.telemetry_json.extend_from_slice(&buf[..len]).unwrap(),
+            Err(error) => log::warn!("Telemetry does not fit: {:?}", error),
+        }
+    }
+
+    /// Publish diagnostics over MQTT.
+    ///
+    /// # Args
+    /// * `diagnostics` - The diagnostics to report.
+    pub fn publish_diagnostics<D: Serialize>(&mut self, diagnostics: &D) {
+        self.telemetry.publish_diagnostics(diagnostics);
+    }
+
+    /// Get the current status of the network users for telemetry reporting.
+    pub fn status(&mut self) -> NetworkStatus {
+        let ip = self
+            .stackref
+            .lock(|stack| stack.interface().ipv4_address())
+            .unwrap_or(Ipv4Address::UNSPECIFIED);
+
+        NetworkStatus {
+            connected: self.telemetry.is_connected(),
+            reconnects: self.telemetry.reconnects(),
+            failures: self.broker.failures(),
+            broker: self.broker.active(),
+            ip: ip.0,
         }
     }
 }
@@ -128,22 +487,67 @@ fn get_client_id(
     identifier
 }
 
+/// The default MQTT prefix template.
+pub const DEFAULT_PREFIX_TEMPLATE: &str = "dt/sinara/{app}/{mac}";
+
 /// Get the MQTT prefix of a device.
 ///
 /// # Args
+/// * `template` - The prefix template. The placeholders `{app}`, `{mac}` and `{alias}` are
+///   replaced by the application name, the MAC address and the device alias.
 /// * `app` - The name of the application that is executing.
 /// * `mac` - The ethernet MAC address of the device.
+/// * `alias` - The device alias.
 ///
 /// # Returns
-/// The MQTT prefix used for this device.
+/// The MQTT prefix used for this device or `None` if the template contains an unknown
+/// placeholder or the prefix is too long.
 pub fn get_device_prefix(
+    template: &str,
     app: &str,
     mac: smoltcp_nal::smoltcp::wire::EthernetAddress,
-) -> String<128> {
-    // Note(unwrap): The mac address + binary name must be short enough to fit into this string. If
-    // they are defined too long, this will panic and the device will fail to boot.
+    alias: &str,
+) -> Option<String<128>> {
     let mut prefix: String<128> = String::new();
-    write!(&mut prefix, "dt/sinara/{}/{}", app, mac).unwrap();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        prefix.push_str(&rest[..start]).ok()?;
+        let end = start + rest[start..].find('}')?;
+        match &rest[start + 1..end] {
+            "app" => prefix.push_str(app).ok()?,
+            "mac" => write!(&mut prefix, "{}", mac).ok()?,
+            "alias" => prefix.push_str(alias).ok()?,
+            _ => return None,
+        }
+        rest = &rest[end + 1..];
+    }
+    prefix.push_str(rest).ok()?;
+
+    Some(prefix)
+}
 
-    prefix
+/// Check that an alias and a prefix template result in a valid MQTT prefix.
+///
+/// # Args
+/// * `template` - The prefix template.
+/// * `app` - The name of the application that is executing.
+/// * `alias` - The device alias.
+///
+/// # Returns
+/// An error if the alias contains topic separators or wildcards, or if the prefix is invalid,
+/// contains wildcards or empty topic levels.
+pub fn validate_prefix(template: &str, app: &str, alias: &str) -> Result<(), &'static str> {
+    if alias.contains(|c| c == '/' || c == '+' || c == '#') {
+        return Err("alias contains '/', '+' or '#'");
+    }
+    // The MAC address has a fixed length and never contains separators or wildcards.
+    let mac = smoltcp_nal::smoltcp::wire::EthernetAddress([0; 6]);
+    let prefix = get_device_prefix(template, app, mac, alias).ok_or("invalid prefix template")?;
+    if prefix.contains(|c| c == '+' || c == '#') {
+        return Err("prefix contains '+' or '#'");
+    }
+    if prefix.split('/').any(str::is_empty) {
+        return Err("prefix contains empty topic levels");
+    }
+    Ok(())
 }
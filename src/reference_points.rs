@@ -0,0 +1,180 @@
+///! Temperature calibration against a reference thermometer
+///!
+///! # Design
+///! The user submits the temperature of a reference thermometer at one or more operating points
+///! of a channel. Each reference is paired with the uncorrected temperature measured at the time
+///! of submission. A linear correction `T = gain * T_measured + offset` is fitted to the points by
+///! least squares. Points spanning less than `MIN_SPAN`, e.g. a single point, only correct the
+///! offset.
+///!
+///! The points are kept in RAM until they are cleared or the device is reset. Only the fitted
+///! correction is persisted with the settings.
+use heapless::Vec;
+
+use crate::TemperatureCalibration;
+
+/// The maximum number of reference points per channel.
+const MAX_POINTS: usize = 8;
+
+/// The minimum temperature span of the points in °C for fitting the gain.
+const MIN_SPAN: f32 = 1.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReferenceError {
+    /// The maximum number of points was reached.
+    Full,
+    /// The measured or the reference temperature is not finite.
+    InvalidPoint,
+    /// The correction is degenerate, e.g. a non-positive gain.
+    InvalidFit,
+}
+
+/// The reference points of a channel.
+#[derive(Clone)]
+pub struct ReferencePoints {
+    /// Pairs of the measured (uncorrected) and the reference temperature in °C.
+    points: Vec<(f32, f32), MAX_POINTS>,
+}
+
+impl ReferencePoints {
+    pub const fn new() -> Self {
+        Self { points: Vec::new() }
+    }
+
+    /// Add a reference point and fit the correction to all points.
+    ///
+    /// # Args
+    /// * `measured` - The uncorrected measured temperature in °C.
+    /// * `reference` - The temperature of the reference thermometer in °C.
+    ///
+    /// # Returns
+    /// The fitted correction.
+    pub fn add(
+        &mut self,
+        measured: f32,
+        reference: f32,
+    ) -> Result<TemperatureCalibration, ReferenceError> {
+        if !measured.is_finite() || !reference.is_finite() {
+            return Err(ReferenceError::InvalidPoint);
+        }
+        self.points
+            .push((measured, reference))
+            .map_err(|_| ReferenceError::Full)?;
+        let fit = self.fit();
+        if fit.is_err() {
+            self.points.pop();
+        }
+        fit
+    }
+
+    /// Remove all points.
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+
+    /// Fit the correction to the points by least squares.
+    fn fit(&self) -> Result<TemperatureCalibration, ReferenceError> {
+        let n = self.points.len() as f32;
+        let mean_x = self.points.iter().map(|(x, _)| x).sum::<f32>() / n;
+        let mean_y = self.points.iter().map(|(_, y)| y).sum::<f32>() / n;
+
+        let (min, max) = self
+            .points
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), (x, _)| {
+                (min.min(*x), max.max(*x))
+            });
+        let gain = if max - min < MIN_SPAN {
+            1.0
+        } else {
+            let sxy: f32 = self
+                .points
+                .iter()
+                .map(|(x, y)| (x - mean_x) * (y - mean_y))
+                .sum();
+            let sxx: f32 = self
+                .points
+                .iter()
+                .map(|(x, _)| (x - mean_x) * (x - mean_x))
+                .sum();
+            sxy / sxx
+        };
+
+        let offset = mean_y - gain * mean_x;
+        if gain <= 0.0 || !gain.is_finite() || !offset.is_finite() {
+            return Err(ReferenceError::InvalidFit);
+        }
+
+        Ok(TemperatureCalibration { gain, offset })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_point_corrects_offset() {
+        let mut points = ReferencePoints::new();
+        let calibration = points.add(25.0, 25.5).unwrap();
+        assert_eq!(calibration.gain, 1.0);
+        assert_eq!(calibration.offset, 0.5);
+    }
+
+    #[test]
+    fn close_points_correct_offset() {
+        let mut points = ReferencePoints::new();
+        points.add(25.0, 25.0).unwrap();
+        let calibration = points.add(25.5, 26.0).unwrap();
+        assert_eq!(calibration.gain, 1.0);
+        assert_eq!(calibration.offset, 0.25);
+    }
+
+    #[test]
+    fn spanning_points_fit_gain() {
+        let mut points = ReferencePoints::new();
+        points.add(20.0, 21.0).unwrap();
+        points.add(30.0, 33.0).unwrap();
+        let calibration = points.add(40.0, 45.0).unwrap();
+        assert!((calibration.gain - 1.2).abs() < 1e-5);
+        assert!((calibration.offset + 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn non_positive_gain_is_rejected() {
+        let mut points = ReferencePoints::new();
+        points.add(20.0, 30.0).unwrap();
+        assert_eq!(points.add(30.0, 20.0), Err(ReferenceError::InvalidFit));
+        // The rejected point is not kept.
+        assert_eq!(points.add(30.0, 40.0).unwrap().gain, 1.0);
+    }
+
+    #[test]
+    fn non_finite_points_are_rejected() {
+        let mut points = ReferencePoints::new();
+        assert_eq!(
+            points.add(f32::NAN, 25.0),
+            Err(ReferenceError::InvalidPoint)
+        );
+        assert_eq!(
+            points.add(25.0, f32::INFINITY),
+            Err(ReferenceError::InvalidPoint)
+        );
+        assert_eq!(
+            points.add(f32::MAX, -f32::MAX),
+            Err(ReferenceError::InvalidFit)
+        );
+        assert_eq!(points.add(25.0, 25.0).unwrap().offset, 0.0);
+    }
+
+    #[test]
+    fn points_are_limited() {
+        let mut points = ReferencePoints::new();
+        for i in 0..MAX_POINTS {
+            points.add(i as f32, i as f32).unwrap();
+        }
+        assert_eq!(points.add(0.0, 0.0), Err(ReferenceError::Full));
+        points.clear();
+        assert!(points.add(0.0, 0.0).is_ok());
+    }
+}
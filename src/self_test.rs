@@ -0,0 +1,65 @@
+///! Boot self-test
+///!
+///! # Design
+///! The self-test runs once during setup, after the ADC, DACs and PWMs were initialized. It checks
+///! that:
+///! * the ADC responds with the ID of an AD7172-2,
+///! * the SPI peripherals of both DACs complete transfers. The DACs can not be read back, so their
+///!   presence is not checked,
+///! * the PWM timers limiting the TEC voltages and currents are running.
+///!
+///! The report is published as a retained message on `<prefix>/self_test`. The TECs can not be
+///! controlled without the ADC, so if it is missing, both TECs are kept shut down and the red LED
+///! stays lit.
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::adc::Adc;
+use crate::dac::{Dacs, Pwms};
+
+/// Results of the boot self-test.
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct SelfTestReport {
+    /// Contents of the ADC ID register. Zero if it could not be read.
+    pub adc_id: u16,
+    /// The ADC responded with the ID of an AD7172-2.
+    pub adc: bool,
+    /// The SPI peripheral of the DAC of each channel completed a transfer. Does not indicate that
+    /// the DAC is present.
+    pub dacs: [bool; 2],
+    /// The PWM timers for the TEC voltage (TIM3) and current (TIM1) limits are running.
+    pub pwms: [bool; 2],
+}
+
+impl SelfTestReport {
+    /// Run the self-test.
+    ///
+    /// # Args
+    /// * `adc` - The initialized ADC.
+    /// * `dacs` - The initialized DACs.
+    /// * `pwms` - The initialized PWMs.
+    ///
+    /// # Returns
+    /// The self-test report.
+    pub fn run(adc: &mut Adc, dacs: &mut Dacs, pwms: &Pwms) -> Self {
+        let report = Self {
+            adc_id: adc.id().unwrap_or(0),
+            adc: adc.is_present(),
+            dacs: dacs.self_test(),
+            pwms: pwms.self_test(),
+        };
+
+        if report.passed() {
+            info!("Self-test passed");
+        } else {
+            warn!("Self-test failed: {:?}", report);
+        }
+
+        report
+    }
+
+    /// Check if all tests passed.
+    pub fn passed(&self) -> bool {
+        self.adc && self.dacs.iter().all(|&ok| ok) && self.pwms.iter().all(|&ok| ok)
+    }
+}
@@ -0,0 +1,395 @@
+///! Settings tree traversal
+///!
+///! # Design
+///! Miniconf addresses individual settings by their path in the settings tree, e.g.
+///! `pidsettings/0/target`. Miniconf itself only supports setting values. This module provides the
+///! reverse direction: any `Serialize` settings tree is walked with a custom serde serializer that
+///! tracks the current path and serializes every leaf value to JSON. The resulting paths and
+///! payloads are identical to what miniconf accepts for the same setting.
+use core::fmt::{self, Write};
+use heapless::{String, Vec};
+use serde::{ser, Serialize};
+
+/// The maximum length of a settings path.
+pub const MAX_PATH_LENGTH: usize = 64;
+
+/// The maximum size of a JSON serialized leaf value.
+pub const MAX_VALUE_SIZE: usize = 128;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// A path does not fit into `MAX_PATH_LENGTH`.
+    PathTooLong,
+    /// A leaf value does not fit into `MAX_VALUE_SIZE` when serialized.
+    ValueTooLarge,
+    /// The settings tree contains a type that has no miniconf path representation.
+    Unsupported,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        Error::Unsupported
+    }
+}
+
+/// Settings that are checked as a whole before they are applied.
+pub trait Validate {
+    /// Check that the settings are within their supported ranges.
+    ///
+    /// # Returns
+    /// A description of the first invalid setting, if any.
+    fn validate(&self) -> Result<(), &'static str>;
+}
+
+/// Visit every leaf of a settings tree.
+///
+/// # Args
+/// * `settings` - The settings tree to traverse.
+/// * `visit` - A closure that is called with the path and JSON value of every leaf, in order.
+pub fn for_each<S, F>(settings: &S, mut visit: F) -> Result<(), Error>
+where
+    S: Serialize,
+    F: FnMut(&str, &[u8]),
+{
+    let mut walker = Walker {
+        path: String::new(),
+        visit: &mut visit,
+    };
+    settings.serialize(&mut walker)
+}
+
+/// Get the path and JSON value of a leaf by its position in the traversal order.
+///
+/// # Args
+/// * `settings` - The settings tree to traverse.
+/// * `index` - The position of the leaf.
+/// * `path` - Storage for the path of the leaf.
+/// * `value` - Storage for the JSON value of the leaf.
+///
+/// # Returns
+/// True if the leaf exists, false if `index` is past the last leaf.
+pub fn nth<S: Serialize>(
+    settings: &S,
+    index: usize,
+    path: &mut String<MAX_PATH_LENGTH>,
+    value: &mut Vec<u8, MAX_VALUE_SIZE>,
+) -> Result<bool, Error> {
+    let mut position = 0;
+    let mut found = false;
+    for_each(settings, |leaf_path, leaf_value| {
+        if position == index {
+            // Note(unwrap): Both were bounded by the same limits during traversal.
+            *path = String::from(leaf_path);
+            *value = Vec::from_slice(leaf_value).unwrap();
+            found = true;
+        }
+        position += 1;
+    })?;
+    Ok(found)
+}
+
+/// Get the JSON value of a leaf by its path.
+///
+/// # Args
+/// * `settings` - The settings tree to traverse.
+/// * `path` - The path of the leaf, e.g. `pidsettings/0/target`.
+/// * `value` - Storage for the JSON value of the leaf.
+///
+/// # Returns
+/// True if the path names a leaf of the tree.
+pub fn get<S: Serialize>(
+    settings: &S,
+    path: &str,
+    value: &mut Vec<u8, MAX_VALUE_SIZE>,
+) -> Result<bool, Error> {
+    let mut found = false;
+    for_each(settings, |leaf_path, leaf_value| {
+        if leaf_path == path {
+            // Note(unwrap): The value was bounded by the same limit during traversal.
+            *value = Vec::from_slice(leaf_value).unwrap();
+            found = true;
+        }
+    })?;
+    Ok(found)
+}
+
+/// A serializer that tracks the path to the current node and reports leaf values.
+struct Walker<'a, F> {
+    path: String<MAX_PATH_LENGTH>,
+    visit: &'a mut F,
+}
+
+impl<'a, F: FnMut(&str, &[u8])> Walker<'a, F> {
+    fn leaf<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let mut buf = [0u8; MAX_VALUE_SIZE];
+        let len = serde_json_core::to_slice(value, &mut buf).map_err(|_| Error::ValueTooLarge)?;
+        (self.visit)(&self.path, &buf[..len]);
+        Ok(())
+    }
+
+    fn child<K, T>(&mut self, key: K, value: &T) -> Result<(), Error>
+    where
+        K: fmt::Display,
+        T: Serialize + ?Sized,
+    {
+        let len = self.path.len();
+        if len > 0 {
+            self.path.push('/').map_err(|_| Error::PathTooLong)?;
+        }
+        write!(self.path, "{}", key).map_err(|_| Error::PathTooLong)?;
+
+        let result = value.serialize(&mut *self);
+        self.path.truncate(len);
+        result
+    }
+}
+
+/// Serializer state for sequences, where children are addressed by index.
+struct Indexed<'b, 'a, F> {
+    walker: &'b mut Walker<'a, F>,
+    index: usize,
+}
+
+impl<'b, 'a, F: FnMut(&str, &[u8])> Indexed<'b, 'a, F> {
+    fn next<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.walker.child(self.index, value)?;
+        self.index += 1;
+        Ok(())
+    }
+}
+
+impl<'b, 'a, F: FnMut(&str, &[u8])> ser::SerializeSeq for Indexed<'b, 'a, F> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.next(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a, F: FnMut(&str, &[u8])> ser::SerializeTuple for Indexed<'b, 'a, F> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.next(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a, F: FnMut(&str, &[u8])> ser::SerializeTupleStruct for Indexed<'b, 'a, F> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.next(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a, F: FnMut(&str, &[u8])> ser::SerializeStruct for &'b mut Walker<'a, F> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.child(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a, F: FnMut(&str, &[u8])> ser::Serializer for &'b mut Walker<'a, F> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Indexed<'b, 'a, F>;
+    type SerializeTuple = Indexed<'b, 'a, F>;
+    type SerializeTupleStruct = Indexed<'b, 'a, F>;
+    type SerializeTupleVariant = ser::Impossible<(), Error>;
+    type SerializeMap = ser::Impossible<(), Error>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = ser::Impossible<(), Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.leaf(&v)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.leaf(&())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.leaf(&())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.leaf(&())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        // Unit variants are represented by their name, which is what miniconf deserializes.
+        self.leaf(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Ok(Indexed {
+            walker: self,
+            index: 0,
+        })
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Ok(Indexed {
+            walker: self,
+            index: 0,
+        })
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Ok(Indexed {
+            walker: self,
+            index: 0,
+        })
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn collect_str<T: fmt::Display + ?Sized>(self, _value: &T) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+}
@@ -1,9 +1,14 @@
-use log::info;
+use log::{info, warn};
 
 use crate::{
     adc::{Adc, AdcPins},
     dac::{Dac0Pins, Dac1Pins, Dacs, Pwms},
+    flash::Flash,
     leds::Leds,
+    self_test::SelfTestReport,
+    settings_tree::Validate,
+    tec_monitor::{TecMonitor, TecMonitorPins},
+    NetworkSettings, Settings, SETTINGS_VERSION,
 };
 
 use smoltcp_nal::smoltcp;
@@ -25,11 +30,13 @@ const HSE: MegaHertz = MegaHertz(8);
 
 type Eth = stm32_eth::Eth<'static, 'static>;
 
-const SRC_MAC: [u8; 6] = [0x80, 0x1f, 0x12, 0x63, 0x84, 0x1a];
+// Address of the 96 bit unique device ID (RM0090 39.1).
+const UID_ADDRESS: *const u32 = 0x1FFF_7A10 as *const u32;
 
-const NUM_TCP_SOCKETS: usize = 2;
-const NUM_UDP_SOCKETS: usize = 0;
-const NUM_SOCKETS: usize = NUM_UDP_SOCKETS + NUM_TCP_SOCKETS;
+const NUM_TCP_SOCKETS: usize = 6;
+const NUM_UDP_SOCKETS: usize = 2;
+const NUM_DHCP_SOCKETS: usize = 1;
+const NUM_SOCKETS: usize = NUM_UDP_SOCKETS + NUM_TCP_SOCKETS + NUM_DHCP_SOCKETS;
 
 pub struct NetStorage {
     pub ip_addrs: [smoltcp::wire::IpCidr; 1],
@@ -38,12 +45,13 @@ pub struct NetStorage {
     pub udp_socket_storage: [UdpSocketStorage; NUM_UDP_SOCKETS],
     pub neighbor_cache: [Option<(smoltcp::wire::IpAddress, smoltcp::iface::Neighbor)>; 4],
     pub routes_cache: [Option<(smoltcp::wire::IpCidr, smoltcp::iface::Route)>; 4],
+    pub ipv4_multicast_groups: [Option<(smoltcp::wire::Ipv4Address, ())>; 1],
 }
 
 #[derive(Copy, Clone)]
 pub struct UdpSocketStorage {
-    rx_storage: [u8; 128],
-    tx_storage: [u8; 128],
+    rx_storage: [u8; 512],
+    tx_storage: [u8; 512],
     tx_metadata: [smoltcp::storage::PacketMetadata<smoltcp::wire::IpEndpoint>; 10],
     rx_metadata: [smoltcp::storage::PacketMetadata<smoltcp::wire::IpEndpoint>; 10],
 }
@@ -51,8 +59,8 @@ pub struct UdpSocketStorage {
 impl UdpSocketStorage {
     const fn new() -> Self {
         Self {
-            rx_storage: [0; 128],
-            tx_storage: [0; 128],
+            rx_storage: [0; 512],
+            tx_storage: [0; 512],
             tx_metadata: [smoltcp::storage::PacketMetadata::<smoltcp::wire::IpEndpoint>::EMPTY; 10],
             rx_metadata: [smoltcp::storage::PacketMetadata::<smoltcp::wire::IpEndpoint>::EMPTY; 10],
         }
@@ -61,15 +69,15 @@ impl UdpSocketStorage {
 
 #[derive(Copy, Clone)]
 pub struct TcpSocketStorage {
-    rx_storage: [u8; 128],
-    tx_storage: [u8; 128],
+    rx_storage: [u8; 1024],
+    tx_storage: [u8; 1024],
 }
 
 impl TcpSocketStorage {
     const fn new() -> Self {
         Self {
-            rx_storage: [0; 128],
-            tx_storage: [0; 128],
+            rx_storage: [0; 1024],
+            tx_storage: [0; 1024],
         }
     }
 }
@@ -77,13 +85,11 @@ impl TcpSocketStorage {
 impl Default for NetStorage {
     fn default() -> Self {
         NetStorage {
-            ip_addrs: [IpCidr::new(
-                IpAddress::from(Ipv4Address::new(10, 42, 0, 18)),
-                24,
-            )],
+            ip_addrs: [IpCidr::new(IpAddress::from(Ipv4Address::UNSPECIFIED), 0)],
             neighbor_cache: [None; 4],
             routes_cache: [None; 4],
-            sockets: [None, None],
+            ipv4_multicast_groups: [None; 1],
+            sockets: Default::default(),
             tcp_socket_storage: [TcpSocketStorage::new(); NUM_TCP_SOCKETS],
             udp_socket_storage: [UdpSocketStorage::new(); NUM_UDP_SOCKETS],
         }
@@ -95,6 +101,8 @@ pub type NetworkStack = smoltcp_nal::NetworkStack<'static, 'static, &'static mut
 pub struct NetworkDevices {
     pub stack: NetworkStack,
     pub mac_address: smoltcp::wire::EthernetAddress,
+    /// The network configuration in use.
+    pub settings: NetworkSettings,
 }
 
 pub struct Thermostat {
@@ -103,6 +111,30 @@ pub struct Thermostat {
     pub adc: Adc,
     pub dacs: Dacs,
     pub pwms: Pwms,
+    pub tec_monitor: TecMonitor,
+    pub flash: Flash,
+    pub settings: Settings,
+    pub self_test: SelfTestReport,
+}
+
+/// Derive the MAC address of the device from its unique device ID.
+///
+/// # Note
+/// The address is locally administered and unicast. Its remaining 46 bits are taken from an
+/// FNV-1a hash of the unique device ID, so it is stable across boots and firmware updates.
+fn device_mac() -> [u8; 6] {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for i in 0..3 {
+        let word = unsafe { UID_ADDRESS.add(i).read_volatile() };
+        for byte in word.to_le_bytes().iter() {
+            hash = (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&hash.to_be_bytes()[..6]);
+    mac[0] = (mac[0] & 0xfc) | 0x02;
+    mac
 }
 
 pub fn setup(core: rtic::Peripherals, device: stm32_eth::stm32::Peripherals) -> Thermostat {
@@ -139,6 +171,19 @@ pub fn setup(core: rtic::Peripherals, device: stm32_eth::stm32::Peripherals) ->
         .pclk2(64.mhz())
         .freeze();
 
+    info!("Load settings");
+    let flash = Flash::new(dp.FLASH);
+    let settings = match flash.load::<Settings>(SETTINGS_VERSION) {
+        Some(settings) => match settings.validate() {
+            Ok(()) => settings,
+            Err(error) => {
+                warn!("Stored settings invalid: {}", error);
+                Settings::default()
+            }
+        },
+        None => Settings::default(),
+    };
+
     // take gpios
     let gpioa = dp.GPIOA.split();
     let gpiob = dp.GPIOB.split();
@@ -203,25 +248,56 @@ pub fn setup(core: rtic::Peripherals, device: stm32_eth::stm32::Peripherals) ->
     info!("Enabling ethernet interrupt");
     eth.enable_interrupt();
 
+    let network_settings = settings.network;
+
     let store = cortex_m::singleton!(: NetStorage = NetStorage::default()).unwrap();
 
     let neighbor_cache = smoltcp::iface::NeighborCache::new(&mut store.neighbor_cache[..]);
 
     let mut routes = Routes::new(&mut store.routes_cache[..]);
-    routes
-        .add_default_ipv4_route(Ipv4Address::UNSPECIFIED)
-        .unwrap();
+
+    // With DHCP, the address and the default route are installed by the network stack once a
+    // lease is acquired, and renewed by it before the lease expires. The DHCP client does not send
+    // DHCPRELEASE, so a lease is released by the server once it expires.
+    if !network_settings.dhcp {
+        store.ip_addrs[0] = IpCidr::new(
+            IpAddress::from(Ipv4Address::from_bytes(&network_settings.ip)),
+            // Note(unwrap): The settings are validated, so the netmask is contiguous.
+            network_settings.prefix_len().unwrap(),
+        );
+        routes
+            .add_default_ipv4_route(Ipv4Address::from_bytes(&network_settings.gateway))
+            .unwrap();
+    }
 
     info!("Setup interface");
 
-    let ethernet_addr = EthernetAddress(SRC_MAC);
-    let interface = InterfaceBuilder::new(eth)
+    let ethernet_addr = match EthernetAddress(network_settings.mac) {
+        mac if mac.is_unicast() && mac.0 != [0; 6] => mac,
+        mac => {
+            if mac.0 != [0; 6] {
+                warn!("Ignoring MAC address override {}: not unicast", mac);
+            }
+            EthernetAddress(device_mac())
+        }
+    };
+    info!("MAC address: {}", ethernet_addr);
+    let mut interface = InterfaceBuilder::new(eth)
         .ethernet_addr(ethernet_addr)
         .ip_addrs(&mut store.ip_addrs[..])
         .neighbor_cache(neighbor_cache)
         .routes(routes)
+        .ipv4_multicast_groups(&mut store.ipv4_multicast_groups[..])
         .finalize();
 
+    // Receive mDNS queries. Membership reports are repeated by the interface on IGMP queries.
+    let mdns_group = Ipv4Address::from_bytes(&crate::mdns::MDNS_GROUP.octets());
+    if let Err(error) =
+        interface.join_multicast_group(mdns_group, smoltcp::time::Instant::from_millis(0))
+    {
+        warn!("Joining the mDNS group failed: {:?}", error);
+    }
+
     info!("Setup sockets");
     let sockets = {
         let mut sockets = smoltcp::socket::SocketSet::new(&mut store.sockets[..]);
@@ -251,6 +327,10 @@ pub fn setup(core: rtic::Peripherals, device: stm32_eth::stm32::Peripherals) ->
             sockets.add(udp_socket);
         }
 
+        if network_settings.dhcp {
+            sockets.add(smoltcp::socket::Dhcpv4Socket::new());
+        }
+
         sockets
     };
 
@@ -260,6 +340,7 @@ pub fn setup(core: rtic::Peripherals, device: stm32_eth::stm32::Peripherals) ->
     let network_devices = NetworkDevices {
         stack,
         mac_address: ethernet_addr,
+        settings: network_settings,
     };
 
     info!("Setup ADC");
@@ -269,7 +350,7 @@ pub fn setup(core: rtic::Peripherals, device: stm32_eth::stm32::Peripherals) ->
         mosi: gpiob.pb15.into_alternate_af5(),
         sync: gpiob.pb12.into_push_pull_output(),
     };
-    let adc = Adc::new(clocks, dp.SPI2, adc_pins);
+    let mut adc = Adc::new(clocks, dp.SPI2, adc_pins);
 
     info!("Setup DACs");
     let dac0_pins = Dac0Pins {
@@ -286,13 +367,33 @@ pub fn setup(core: rtic::Peripherals, device: stm32_eth::stm32::Peripherals) ->
         shdn: gpioe.pe15.into_push_pull_output(),
     };
 
-    let dacs = Dacs::new(clocks, dp.SPI4, dp.SPI5, dac0_pins, dac1_pins);
+    let mut dacs = Dacs::new(clocks, dp.SPI4, dp.SPI5, dac0_pins, dac1_pins);
 
     let pwms = Pwms::new(
         clocks, tim1, tim3, gpioc.pc6, gpioc.pc7, gpioe.pe9, gpioe.pe11, gpioe.pe13, gpioe.pe14,
     );
 
-    leds.r1.off();
+    info!("Setup TEC monitor");
+    let tec_monitor_pins = TecMonitorPins {
+        vref0: gpioa.pa0.into_analog(),
+        itec0: gpioa.pa6.into_analog(),
+        vtec0: gpioc.pc2.into_analog(),
+        vref1: gpioa.pa3.into_analog(),
+        itec1: gpiob.pb0.into_analog(),
+        vtec1: gpioc.pc3.into_analog(),
+    };
+    let tec_monitor = TecMonitor::new(dp.ADC1, tec_monitor_pins);
+
+    info!("Run self-test");
+    let self_test = SelfTestReport::run(&mut adc, &mut dacs, &pwms);
+    if self_test.adc {
+        leds.r1.off();
+    } else {
+        // Without temperature readings the TECs can not be controlled safely.
+        warn!("ADC missing, keeping the TECs shut down");
+        dacs.inhibit();
+    }
+
     info!("---Setup Done");
 
     let thermostat = Thermostat {
@@ -301,6 +402,10 @@ pub fn setup(core: rtic::Peripherals, device: stm32_eth::stm32::Peripherals) ->
         adc,
         dacs,
         pwms,
+        tec_monitor,
+        flash,
+        settings,
+        self_test,
     };
 
     thermostat
@@ -70,6 +70,15 @@ where
     forward! {close(socket: S::TcpSocket) -> Result<(), S::Error>}
 }
 
+impl<'a, S> embedded_nal::TcpFullStack for NetworkStackProxy<'a, S>
+where
+    S: embedded_nal::TcpFullStack,
+{
+    forward! {bind(socket: &mut S::TcpSocket, local_port: u16) -> Result<(), S::Error>}
+    forward! {listen(socket: &mut S::TcpSocket) -> Result<(), S::Error>}
+    forward! {accept(socket: &mut S::TcpSocket) -> embedded_nal::nb::Result<(S::TcpSocket, embedded_nal::SocketAddr), S::Error>}
+}
+
 impl<'a, S> embedded_nal::UdpClientStack for NetworkStackProxy<'a, S>
 where
     S: embedded_nal::UdpClientStack,
@@ -85,6 +94,14 @@ where
     forward! {close(socket: S::UdpSocket) -> Result<(), S::Error>}
 }
 
+impl<'a, S> embedded_nal::UdpFullStack for NetworkStackProxy<'a, S>
+where
+    S: embedded_nal::UdpFullStack,
+{
+    forward! {bind(socket: &mut S::UdpSocket, local_port: u16) -> Result<(), S::Error>}
+    forward! {send_to(socket: &mut S::UdpSocket, remote: embedded_nal::SocketAddr, buffer: &[u8]) -> embedded_nal::nb::Result<(), S::Error>}
+}
+
 impl NetworkManager {
     /// Construct a new manager for a shared network stack
     ///
@@ -0,0 +1,56 @@
+///! Thermostat system timer
+///!
+///! # Design
+///! The DWT cycle counter (CYCCNT) is a free-running 32-bit counter clocked at the core frequency.
+///! At 168 MHz it wraps roughly every 25.6 s, so it can not be used directly as an uptime
+///! reference. This module extends it to 64 bits by tracking the number of wraps.
+///!
+///! # Note
+///! The extension relies on the counter being read at least once per wrap period. `poll_eth`
+///! reads it every millisecond. All readers execute at the same RTIC priority, so the update of
+///! the wrap state is never pre-empted by another reader.
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::DWT;
+use embedded_time::{clock, fraction::Fraction, Instant};
+
+use crate::CYC_PER_S;
+
+/// The last observed value of the cycle counter.
+static LAST_CYCCNT: AtomicU32 = AtomicU32::new(0);
+
+/// The number of cycle counter wraps observed so far.
+static WRAPS: AtomicU32 = AtomicU32::new(0);
+
+/// A monotonic time source derived from CYCCNT.
+#[derive(Copy, Clone, Default)]
+pub struct SystemTimer;
+
+impl SystemTimer {
+    /// Get the number of core clock cycles elapsed since boot.
+    pub fn ticks() -> u64 {
+        let now = DWT::cycle_count();
+        let last = LAST_CYCCNT.swap(now, Ordering::Relaxed);
+        let wraps = if now < last {
+            WRAPS.fetch_add(1, Ordering::Relaxed) + 1
+        } else {
+            WRAPS.load(Ordering::Relaxed)
+        };
+
+        ((wraps as u64) << 32) | now as u64
+    }
+
+    /// Get the number of milliseconds elapsed since boot.
+    pub fn millis() -> u64 {
+        Self::ticks() / (CYC_PER_S / 1000) as u64
+    }
+}
+
+impl embedded_time::Clock for SystemTimer {
+    type T = u32;
+
+    const SCALING_FACTOR: Fraction = Fraction::new(1, 1000);
+
+    fn try_now(&self) -> Result<Instant<Self>, clock::Error> {
+        Ok(Instant::new(Self::millis() as u32))
+    }
+}
@@ -0,0 +1,123 @@
+///! TCP server socket management
+///!
+///! # Design
+///! The servers of the device accept a single client at a time. This module manages the
+///! listening socket and the connection of such a server on top of the shared network stack. When
+///! the connection is closed, the listening socket is recreated, as the network stack may have used
+///! it for the connection.
+use minimq::embedded_nal::{nb, TcpClientStack, TcpFullStack};
+
+use crate::network_users::NetworkReference;
+
+type TcpSocket = <NetworkReference as TcpClientStack>::TcpSocket;
+
+pub struct TcpServer {
+    stack: NetworkReference,
+    port: u16,
+    listener: Option<TcpSocket>,
+    connection: Option<TcpSocket>,
+}
+
+impl TcpServer {
+    /// Construct a new server.
+    ///
+    /// # Args
+    /// * `stack` - A reference to the (shared) underlying network stack.
+    /// * `port` - The TCP port to listen on. Zero disables the server.
+    pub fn new(stack: NetworkReference, port: u16) -> Self {
+        Self {
+            stack,
+            port,
+            listener: None,
+            connection: None,
+        }
+    }
+
+    /// Listen for and accept a client.
+    ///
+    /// # Returns
+    /// True if a client is connected.
+    pub fn poll(&mut self) -> bool {
+        if self.port == 0 {
+            return false;
+        }
+
+        if let Some(connection) = self.connection.as_ref() {
+            match TcpClientStack::is_connected(&mut self.stack, connection) {
+                Ok(true) => return true,
+                _ => self.close(),
+            }
+        }
+
+        if self.listener.is_none() {
+            let mut socket = match TcpClientStack::socket(&mut self.stack) {
+                Ok(socket) => socket,
+                Err(_) => return false,
+            };
+            let result = TcpFullStack::bind(&mut self.stack, &mut socket, self.port)
+                .and_then(|_| TcpFullStack::listen(&mut self.stack, &mut socket));
+            match result {
+                Ok(()) => self.listener = Some(socket),
+                Err(error) => {
+                    log::warn!("Listening on port {} failed: {:?}", self.port, error);
+                    TcpClientStack::close(&mut self.stack, socket).ok();
+                    return false;
+                }
+            }
+        }
+
+        // Note(unwrap): The listener was created above.
+        let listener = self.listener.as_mut().unwrap();
+        match TcpFullStack::accept(&mut self.stack, listener) {
+            Ok((connection, remote)) => {
+                log::info!("Port {}: accepted connection from {}", self.port, remote);
+                self.connection = Some(connection);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Receive from the client.
+    ///
+    /// # Returns
+    /// The number of bytes received or `Err` if there is no connection. A failed connection is
+    /// closed.
+    pub fn receive(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        let connection = self.connection.as_mut().ok_or(())?;
+        match TcpClientStack::receive(&mut self.stack, connection, buf) {
+            Ok(len) => Ok(len),
+            Err(nb::Error::WouldBlock) => Ok(0),
+            Err(nb::Error::Other(_)) => {
+                self.close();
+                Err(())
+            }
+        }
+    }
+
+    /// Send to the client.
+    ///
+    /// # Returns
+    /// The number of bytes sent or `Err` if there is no connection. A failed connection is closed.
+    pub fn send(&mut self, buf: &[u8]) -> Result<usize, ()> {
+        let connection = self.connection.as_mut().ok_or(())?;
+        match TcpClientStack::send(&mut self.stack, connection, buf) {
+            Ok(len) => Ok(len),
+            Err(nb::Error::WouldBlock) => Ok(0),
+            Err(nb::Error::Other(_)) => {
+                self.close();
+                Err(())
+            }
+        }
+    }
+
+    /// Close the connection and listen for the next client.
+    pub fn close(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            TcpClientStack::close(&mut self.stack, connection).ok();
+        }
+        if let Some(listener) = self.listener.take() {
+            TcpClientStack::close(&mut self.stack, listener).ok();
+        }
+    }
+}
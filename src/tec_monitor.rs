@@ -0,0 +1,154 @@
+///! TEC current and voltage readback
+///!
+///! # Design
+///! The MAX1968 TEC drivers provide the TEC current (ITEC) and voltage (VDIFF) as analog outputs
+///! relative to their 1.5 V reference (REF). The outputs and the reference of both channels are
+///! sampled with the internal ADC1 of the STM32:
+///! * `I = (V_ITEC - V_REF) / (8 * R_SENSE)`
+///! * `U = (V_VDIFF - V_REF) * 4`
+///!
+///! The measured current is compared against the commanded one. A mismatch is only flagged if it
+///! persists for several readbacks, so settling after a change of the output does not trigger it.
+///!
+///! ADC1 additionally measures the internal reference (VREFINT) and die temperature of the STM32
+///! for diagnostics.
+use num_traits::float::Float;
+use stm32_eth::hal::{
+    adc::{
+        config::{AdcConfig, SampleTime},
+        Adc, Temperature, Vref,
+    },
+    gpio::{
+        gpioa::{PA0, PA3, PA6},
+        gpiob::PB0,
+        gpioc::{PC2, PC3},
+        Analog,
+    },
+    hal::adc::Channel,
+    signature::{VtempCal110, VtempCal30},
+    stm32::ADC1,
+};
+
+use crate::unit_conversion::{itec_to_i, vtec_to_v};
+
+/// The maximum difference between the commanded and measured current in A.
+const MAX_CURRENT_MISMATCH: f32 = 0.1;
+
+/// The number of consecutive readbacks with a mismatch before it is flagged.
+const MISMATCH_READBACKS: u32 = 5;
+
+pub struct TecMonitorPins {
+    pub vref0: PA0<Analog>,
+    pub itec0: PA6<Analog>,
+    pub vtec0: PC2<Analog>,
+    pub vref1: PA3<Analog>,
+    pub itec1: PB0<Analog>,
+    pub vtec1: PC3<Analog>,
+}
+
+/// Measured TEC current and voltage of a channel.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TecReadback {
+    /// TEC current in A.
+    pub current: f32,
+    /// TEC voltage in V.
+    pub voltage: f32,
+    /// Indicates that the measured current persistently deviates from the commanded one.
+    pub mismatch: bool,
+}
+
+pub struct TecMonitor {
+    adc: Adc<ADC1>,
+    pins: TecMonitorPins,
+    /// Number of consecutive readbacks with a current mismatch per channel.
+    mismatches: [u32; 2],
+}
+
+impl TecMonitor {
+    pub fn new(adc1: ADC1, pins: TecMonitorPins) -> Self {
+        let mut adc = Adc::adc1(adc1, true, AdcConfig::default());
+        adc.enable_temperature_and_vref();
+        Self {
+            adc,
+            pins,
+            mismatches: [0; 2],
+        }
+    }
+
+    /// Sample a pin and convert the sample to volts.
+    fn sample<P>(adc: &mut Adc<ADC1>, pin: &P) -> f32
+    where
+        P: Channel<ADC1, ID = u8>,
+    {
+        let sample = adc.convert(pin, SampleTime::Cycles_480);
+        adc.sample_to_millivolts(sample) as f32 / 1000.0
+    }
+
+    /// Measure the TEC current and voltage of a channel.
+    ///
+    /// # Args
+    /// * `ch` - The thermostat channel.
+    /// * `commanded` - The commanded TEC current in A, limited to the current limits of the
+    ///   channel. `None` if the channel is shut down.
+    ///
+    /// # Returns
+    /// The measured current and voltage.
+    pub fn measure(&mut self, ch: usize, commanded: Option<f32>) -> TecReadback {
+        let adc = &mut self.adc;
+        let pins = &self.pins;
+        let (vref, itec, vtec) = match ch {
+            0 => (
+                Self::sample(adc, &pins.vref0),
+                Self::sample(adc, &pins.itec0),
+                Self::sample(adc, &pins.vtec0),
+            ),
+            _ => (
+                Self::sample(adc, &pins.vref1),
+                Self::sample(adc, &pins.itec1),
+                Self::sample(adc, &pins.vtec1),
+            ),
+        };
+        let current = itec_to_i(itec, vref);
+        let voltage = vtec_to_v(vtec, vref);
+
+        // A shut down driver does not drive any current.
+        let commanded = commanded.unwrap_or(0.0);
+        if (current - commanded).abs() > MAX_CURRENT_MISMATCH {
+            self.mismatches[ch] += 1;
+            if self.mismatches[ch] == MISMATCH_READBACKS {
+                log::warn!(
+                    "TEC current mismatch on channel {}: commanded {} A, measured {} A",
+                    ch,
+                    commanded,
+                    current
+                );
+            }
+        } else {
+            self.mismatches[ch] = 0;
+        }
+
+        TecReadback {
+            current,
+            voltage,
+            mismatch: self.mismatches[ch] >= MISMATCH_READBACKS,
+        }
+    }
+
+    /// Measure the internal reference and die temperature of the STM32.
+    ///
+    /// # Returns
+    /// The internal reference voltage and the die temperature in °C.
+    pub fn measure_mcu(&mut self) -> (f32, f32) {
+        let vrefint = Self::sample(&mut self.adc, &Vref);
+
+        // The temperature sensor is calibrated at 3.3 V.
+        let sample = self.adc.convert(&Temperature, SampleTime::Cycles_480) as f32
+            * self.adc.reference_voltage() as f32
+            / 3300.0;
+        let cal30 = VtempCal30::get().read() as f32;
+        let cal110 = VtempCal110::get().read() as f32;
+        let temperature = (110.0 - 30.0) * (sample - cal30) / (cal110 - cal30) + 30.0;
+
+        (vrefint, temperature)
+    }
+}
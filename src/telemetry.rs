@@ -3,7 +3,8 @@
 ///!
 ///! # Design
 ///! Telemetry is reported regularly using an MQTT client. All telemetry is reported in SI units
-///! using standard JSON format.
+///! using either standard JSON or the compact CBOR or MessagePack encodings, as selected in the
+///! settings.
 ///!
 ///! In order to report ADC/DAC codes generated during the DSP routines, a telemetry buffer is
 ///! employed to track the latest codes. Converting these codes to SI units would result in
@@ -11,21 +12,85 @@
 ///! sampling frequency. Instead, the raw codes are stored and the telemetry is generated as
 ///! required immediately before transmission. This ensures that any slower computation required
 ///! for unit conversion can be off-loaded to lower priority tasks.
-use heapless::{String, Vec};
-use minimq::QoS;
-use serde::Serialize;
+use heapless::String;
+use miniconf::Miniconf;
+use minimq::{QoS, Retain};
+use serde::{Deserialize, Serialize};
 
-use crate::network_users::NetworkReference;
-use crate::unit_conversion::{adc_to_temp, dac_to_i};
-use minimq::embedded_nal::IpAddr;
+use crate::broker::BrokerStack;
+use crate::msgpack;
+use crate::network_users::NetworkStatus;
+use crate::system_timer::SystemTimer;
+use crate::unit_conversion::{adc_to_temp, adc_to_volts, dac_to_i};
+use crate::TemperatureCalibration;
+use minimq::embedded_nal::{IpAddr, Ipv4Addr};
+
+/// The size of the MQTT message buffer of the telemetry client.
+const MQTT_BUFFER_SIZE: usize = 1024;
+
+/// The maximum size of a serialized telemetry payload.
+const PAYLOAD_SIZE: usize = 768;
 
 /// The telemetry client for reporting telemetry data over MQTT.
 pub struct TelemetryClient<T: Serialize> {
-    mqtt: minimq::Minimq<NetworkReference, 256>,
+    mqtt: minimq::Minimq<BrokerStack, SystemTimer, MQTT_BUFFER_SIZE, 1>,
     telemetry_topic: String<128>,
+    diagnostics_topic: String<128>,
+    alive_topic: String<128>,
+    encoding: TelemetryEncoding,
+    connected: bool,
+    connections: u32,
     _telemetry: core::marker::PhantomData<T>,
 }
 
+/// The payload encoding used for telemetry messages.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Miniconf)]
+pub enum TelemetryEncoding {
+    /// Human-readable JSON.
+    Json,
+    /// Compact binary CBOR (RFC 8949) with named fields.
+    Cbor,
+    /// Compact binary MessagePack with named fields.
+    MessagePack,
+}
+
+/// Errors that may occur while encoding telemetry.
+#[derive(Debug)]
+pub enum EncodingError {
+    Json(serde_json_core::ser::Error),
+    Cbor(serde_cbor::Error),
+    MessagePack(msgpack::Error),
+}
+
+impl TelemetryEncoding {
+    /// Serialize a value into a buffer.
+    ///
+    /// # Args
+    /// * `value` - The value to serialize.
+    /// * `buf` - The buffer to serialize into.
+    ///
+    /// # Returns
+    /// The number of bytes written into `buf`.
+    pub fn encode<T: Serialize>(&self, value: &T, buf: &mut [u8]) -> Result<usize, EncodingError> {
+        match self {
+            TelemetryEncoding::Json => {
+                serde_json_core::to_slice(value, buf).map_err(EncodingError::Json)
+            }
+            TelemetryEncoding::Cbor => {
+                let writer = serde_cbor::ser::SliceWrite::new(buf);
+                let mut serializer = serde_cbor::Serializer::new(writer);
+                value
+                    .serialize(&mut serializer)
+                    .map_err(EncodingError::Cbor)?;
+                Ok(serializer.into_inner().bytes_written())
+            }
+            TelemetryEncoding::MessagePack => {
+                msgpack::to_slice(value, buf).map_err(EncodingError::MessagePack)
+            }
+        }
+    }
+}
+
 /// The telemetry buffer is used for storing sample values during execution.
 ///
 /// # Note
@@ -36,6 +101,15 @@ pub struct TelemetryClient<T: Serialize> {
 pub struct TelemetryBuffer {
     pub adcs: [u32; 2],
     pub dacs: [u32; 2],
+    pub samples: [u32; 2],
+    pub adc_errors: [u32; 2],
+    pub adc_crc_errors: u32,
+    pub adc_reinits: u32,
+    pub aux: [u32; 2],
+    pub tec_current: [f32; 2],
+    pub tec_voltage: [f32; 2],
+    pub tec_mismatch: [bool; 2],
+    pub overruns: u32,
 }
 
 impl Default for TelemetryBuffer {
@@ -43,6 +117,15 @@ impl Default for TelemetryBuffer {
         Self {
             adcs: [0, 0],
             dacs: [0, 0],
+            samples: [0, 0],
+            adc_errors: [0, 0],
+            adc_crc_errors: 0,
+            adc_reinits: 0,
+            aux: [0, 0],
+            tec_current: [0.0, 0.0],
+            tec_voltage: [0.0, 0.0],
+            tec_mismatch: [false, false],
+            overruns: 0,
         }
     }
 }
@@ -54,15 +137,50 @@ impl Default for TelemetryBuffer {
 /// overhead.
 #[derive(Serialize)]
 pub struct Telemetry {
+    /// Milliseconds since boot at the time the telemetry was generated.
+    pub timestamp: u64,
+    pub network: NetworkStatus,
     pub dacs: [f32; 2],
     pub adcs: [f32; 2],
+    /// Number of ADC samples acquired per channel since boot.
+    pub samples: [u32; 2],
+    /// Number of samples per channel discarded because the ADC reported an error.
+    pub adc_errors: [u32; 2],
+    /// Number of ADC SPI transactions with a checksum mismatch.
+    pub adc_crc_errors: u32,
+    /// Number of times the ADC was reinitialized because its configuration diverged.
+    pub adc_reinits: u32,
+    /// Input voltage of the auxiliary ADC channels in V.
+    pub aux: [f32; 2],
+    /// Measured TEC current per channel in A.
+    pub tec_current: [f32; 2],
+    /// Measured TEC voltage per channel in V.
+    pub tec_voltage: [f32; 2],
+    /// Electrical power per TEC in W.
+    pub tec_power: [f32; 2],
+    /// Indicates a persistent mismatch between the commanded and measured TEC current.
+    pub tec_mismatch: [bool; 2],
+    /// Number of samples dropped because `process` could not be spawned.
+    pub overruns: u32,
 }
 
 impl Default for Telemetry {
     fn default() -> Self {
         Self {
+            timestamp: 0,
+            network: NetworkStatus::default(),
             dacs: [0.0, 0.0],
             adcs: [0.0, 0.0],
+            samples: [0, 0],
+            adc_errors: [0, 0],
+            adc_crc_errors: 0,
+            adc_reinits: 0,
+            aux: [0.0, 0.0],
+            tec_current: [0.0, 0.0],
+            tec_voltage: [0.0, 0.0],
+            tec_power: [0.0, 0.0],
+            tec_mismatch: [false, false],
+            overruns: 0,
         }
     }
 }
@@ -71,15 +189,39 @@ impl TelemetryBuffer {
     /// Convert the telemetry buffer to finalized, SI-unit telemetry for reporting.
     ///
     /// # Args
-    /// * `afe0` - The current AFE configuration for channel 0.
-    /// * `afe1` - The current AFE configuration for channel 1.
+    /// * `timestamp` - The system uptime in milliseconds.
+    /// * `network` - The current status of the network users.
+    /// * `calibration` - The temperature correction of each channel.
     ///
     /// # Returns
     /// The finalized telemetry structure that can be serialized and reported.
-    pub fn finalize(self) -> Telemetry {
+    pub fn finalize(
+        self,
+        timestamp: u64,
+        network: NetworkStatus,
+        calibration: &[TemperatureCalibration; 2],
+    ) -> Telemetry {
         Telemetry {
-            adcs: [adc_to_temp(self.adcs[0]), adc_to_temp(self.adcs[1])],
+            timestamp,
+            network,
+            adcs: [
+                adc_to_temp(self.adcs[0], &calibration[0]),
+                adc_to_temp(self.adcs[1], &calibration[1]),
+            ],
             dacs: [dac_to_i(self.dacs[0]), dac_to_i(self.dacs[1])],
+            samples: self.samples,
+            adc_errors: self.adc_errors,
+            adc_crc_errors: self.adc_crc_errors,
+            adc_reinits: self.adc_reinits,
+            aux: [adc_to_volts(self.aux[0]), adc_to_volts(self.aux[1])],
+            tec_current: self.tec_current,
+            tec_voltage: self.tec_voltage,
+            tec_power: [
+                self.tec_current[0] * self.tec_voltage[0],
+                self.tec_current[1] * self.tec_voltage[1],
+            ],
+            tec_mismatch: self.tec_mismatch,
+            overruns: self.overruns,
         }
     }
 }
@@ -87,56 +229,224 @@ impl TelemetryBuffer {
 impl<T: Serialize> TelemetryClient<T> {
     /// Construct a new telemetry client.
     ///
+    /// # Note
+    /// The client registers a retained last-will message of `0` on `<prefix>/alive` and publishes a
+    /// retained `1` to the same topic whenever it connects to the broker. This allows observers to
+    /// distinguish a disconnected device from a quiet one.
+    ///
     /// # Args
-    /// * `stack` - A reference to the (shared) underlying network stack.
+    /// * `stack` - A proxy of the (shared) underlying network stack connecting to the broker.
     /// * `client_id` - The MQTT client ID of the telemetry client.
     /// * `prefix` - The device prefix to use for MQTT telemetry reporting.
-    /// * `broker` - The IP address of the MQTT broker to use.
     ///
     /// # Returns
     /// A new telemetry client.
-    pub fn new(stack: NetworkReference, client_id: &str, prefix: &str, broker: IpAddr) -> Self {
-        let mqtt = minimq::Minimq::new(broker, client_id, stack).unwrap();
+    pub fn new(stack: BrokerStack, client_id: &str, prefix: &str) -> Self {
+        let mut mqtt = minimq::Minimq::new(
+            // The broker address is substituted by the `BrokerStack`.
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            client_id,
+            stack,
+            SystemTimer,
+        )
+        .unwrap();
 
         let mut telemetry_topic: String<128> = String::from(prefix);
         telemetry_topic.push_str("/telemetry").unwrap();
 
+        let mut diagnostics_topic: String<128> = String::from(prefix);
+        diagnostics_topic.push_str("/diagnostics").unwrap();
+
+        let mut alive_topic: String<128> = String::from(prefix);
+        alive_topic.push_str("/alive").unwrap();
+
+        // Note(unwrap): The will is set before the client connected, which can not fail.
+        mqtt.client
+            .set_will(&alive_topic, b"0", QoS::AtMostOnce, Retain::Retained, &[])
+            .unwrap();
+
         Self {
             mqtt,
             telemetry_topic,
+            diagnostics_topic,
+            alive_topic,
+            encoding: TelemetryEncoding::Json,
+            connected: false,
+            connections: 0,
             _telemetry: core::marker::PhantomData::default(),
         }
     }
 
+    /// Select the payload encoding of subsequent telemetry messages.
+    ///
+    /// # Args
+    /// * `encoding` - The encoding to use.
+    pub fn set_encoding(&mut self, encoding: TelemetryEncoding) {
+        self.encoding = encoding;
+    }
+
     /// Publish telemetry over MQTT
     ///
     /// # Note
     /// Telemetry is reported in a "best-effort" fashion. Failure to transmit telemetry will cause
-    /// it to be silently dropped.
+    /// it to be silently dropped. Telemetry that fails to serialize (e.g. because it does not fit
+    /// into the payload buffer) is dropped with a warning.
     ///
     /// # Args
     /// * `telemetry` - The telemetry to report
     pub fn publish(&mut self, telemetry: &T) {
-        let telemetry: Vec<u8, 256> = serde_json_core::to_vec(telemetry).unwrap();
+        let topic = self.telemetry_topic.clone();
+        self.publish_message(&topic, telemetry);
+    }
+
+    /// Publish diagnostics over MQTT on `<prefix>/diagnostics`
+    ///
+    /// # Note
+    /// Diagnostics are reported the same way as telemetry.
+    ///
+    /// # Args
+    /// * `diagnostics` - The diagnostics to report
+    pub fn publish_diagnostics<D: Serialize>(&mut self, diagnostics: &D) {
+        let topic = self.diagnostics_topic.clone();
+        self.publish_message(&topic, diagnostics);
+    }
+
+    /// Encode and publish a message in a "best-effort" fashion.
+    fn publish_message<M: Serialize>(&mut self, topic: &str, message: &M) {
+        let mut payload = [0u8; PAYLOAD_SIZE];
+        let len = match self.encoding.encode(message, &mut payload) {
+            Ok(len) => len,
+            Err(error) => {
+                log::warn!("Serialization of {} failed: {:?}", topic, error);
+                return;
+            }
+        };
 
         self.mqtt
             .client
-            .publish(&self.telemetry_topic, &telemetry, QoS::AtMostOnce, &[])
+            .publish(
+                topic,
+                &payload[..len],
+                QoS::AtMostOnce,
+                Retain::NotRetained,
+                &[],
+            )
             .ok();
     }
 
+    /// Publish a retained message over MQTT.
+    ///
+    /// # Args
+    /// * `topic` - The full topic to publish to.
+    /// * `payload` - The message payload.
+    pub fn publish_retained(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<(), minimq::Error<smoltcp_nal::NetworkError>> {
+        self.mqtt
+            .client
+            .publish(topic, payload, QoS::AtMostOnce, Retain::Retained, &[])
+    }
+
+    /// Check if the client is currently connected to the broker.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Get the number of times the client re-established a lost broker connection.
+    pub fn reconnects(&self) -> u32 {
+        self.connections.saturating_sub(1)
+    }
+
     /// Update the telemetry client
     ///
     /// # Note
     /// This function is provided to force the underlying MQTT state machine to process incoming
     /// and outgoing messages. Without this, the client will never connect to the broker. This
     /// should be called regularly.
-    pub fn update(&mut self) {
+    ///
+    /// # Returns
+    /// True if the client (re-)connected to the broker during this update.
+    pub fn update(&mut self) -> bool {
         match self.mqtt.poll(|_client, _topic, _message, _properties| {}) {
             Err(minimq::Error::Network(smoltcp_nal::NetworkError::NoIpAddress)) => {}
 
             Err(error) => log::info!("Unexpected error: {:?}", error),
             _ => {}
         }
+
+        let connected = self.mqtt.client.is_connected();
+        let established = connected && !self.connected;
+        if established {
+            self.connections += 1;
+            log::info!(
+                "Telemetry client connected ({} connections)",
+                self.connections
+            );
+
+            // A failure to publish leaves the previous retained value (or the will) in place.
+            if self
+                .mqtt
+                .client
+                .publish(
+                    &self.alive_topic,
+                    b"1",
+                    QoS::AtMostOnce,
+                    Retain::Retained,
+                    &[],
+                )
+                .is_err()
+            {
+                log::warn!("Failed to publish alive status");
+            }
+        }
+        self.connected = connected;
+        established
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Message {
+        a: u32,
+        b: f32,
+    }
+
+    const MESSAGE: Message = Message { a: 1, b: 1.5 };
+
+    #[test]
+    fn cbor() {
+        let mut buf = [0u8; 32];
+        let len = TelemetryEncoding::Cbor.encode(&MESSAGE, &mut buf).unwrap();
+        // Lossless floats are encoded as half precision (RFC 8949 appendix A: 1.5 is 0xf93e00).
+        assert_eq!(
+            buf[..len],
+            [0xa2, 0x61, b'a', 0x01, 0x61, b'b', 0xf9, 0x3e, 0x00]
+        );
+    }
+
+    #[test]
+    fn message_pack() {
+        let mut buf = [0u8; 32];
+        let len = TelemetryEncoding::MessagePack
+            .encode(&MESSAGE, &mut buf)
+            .unwrap();
+        assert_eq!(
+            buf[..len],
+            [0x82, 0xa1, b'a', 0x01, 0xa1, b'b', 0xca, 0x3f, 0xc0, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn buffer_full() {
+        let mut buf = [0u8; 4];
+        assert!(TelemetryEncoding::Cbor.encode(&MESSAGE, &mut buf).is_err());
+        assert!(TelemetryEncoding::MessagePack
+            .encode(&MESSAGE, &mut buf)
+            .is_err());
     }
 }
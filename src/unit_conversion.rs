@@ -4,9 +4,14 @@
 use core::f32;
 use num_traits::float::Float;
 
+use crate::TemperatureCalibration;
+
 // ADC constants
 const GAIN: u32 = 0x555555; // default ADC gain from datasheet
 const R_INNER: f32 = 2.0 * 5100.0; // ratiometric resistor setup. 5.1k high and low side.
+const VREF_ADC: f32 = 2.5; // internal ADC reference voltage used by the auxiliary channels
+const TEMP_SENSOR_GAIN: f32 = 477e-6; // ADC temperature sensor output in V/K
+const SUPPLY_DIVIDER: f32 = 5.0; // ADC supply diagnostic input divider
 
 // Steinhart-Hart Parameters
 const ZEROK: f32 = 273.15; // 0°C in °K
@@ -15,11 +20,13 @@ const T_N_INV: f32 = 1.0 / (25.0 + ZEROK); // T_n = 25°C
 const R_N: f32 = 10000.0; // TEC resistance at 25°C
 
 // PWM constants
-const MAXV: f32 = 5.0; // maximum voltage configurable for TEC driver
+pub const MAXV: f32 = 5.0; // maximum voltage configurable for TEC driver
 pub const MAXI: f32 = 3.0; // maximum current configurable for TEC driver
 
 // DAC constants
 const R_SENSE: f32 = 0.05; // TEC current sense resistor
+const ITEC_GAIN: f32 = 8.0; // TEC driver current monitor gain
+const VTEC_GAIN: f32 = 4.0; // TEC driver inverse voltage monitor gain
 pub const VREF_TEC: f32 = 1.5; // TEC driver reference voltage
 const MAXCODE: f32 = (1 << 18) as _; // maximum DAC dataword
 const VREF_OS: f32 = 0.025; // Device specific offset voltage for zero current at half dac scale
@@ -30,7 +37,11 @@ const DATAWIDTH_GAIN: f32 = 0.015625; // 2**-6 LSB to LSB gain from 24 to 18 bit
 const SCALE: f32 = (1 << 23) as _; // half the ADC maximum dataword
 
 /// Convert raw adc code to temperature in °C.
-pub fn adc_to_temp(adc: u32) -> f32 {
+///
+/// # Args
+/// * `adc` - The raw adc code.
+/// * `calibration` - The correction of the channel against a reference thermometer.
+pub fn adc_to_temp(adc: u32, calibration: &TemperatureCalibration) -> f32 {
     // raw to R
     let data = (adc as f32) * (0.5 * 0x400000 as f32 / GAIN as f32);
     let vin = data as f32 / (0.75 * SCALE);
@@ -38,7 +49,29 @@ pub fn adc_to_temp(adc: u32) -> f32 {
 
     // R to T (°C)
     let t_inv = T_N_INV + (1.0 / B) * (r / R_N).ln();
-    (1.0 / t_inv) - ZEROK
+    let temp = (1.0 / t_inv) - ZEROK;
+    calibration.gain * temp + calibration.offset
+}
+
+/// Convert raw adc code to the input voltage relative to the reference voltage.
+pub fn adc_to_ratio(adc: u32) -> f32 {
+    let data = (adc as f32) * (0.5 * 0x400000 as f32 / GAIN as f32);
+    data / (0.75 * SCALE)
+}
+
+/// Convert raw adc code of a conversion against the internal reference to the input voltage.
+pub fn adc_to_volts(adc: u32) -> f32 {
+    VREF_ADC * adc_to_ratio(adc)
+}
+
+/// Convert raw adc code of the ADC temperature sensor to the ADC temperature in °C.
+pub fn adc_to_die_temp(adc: u32) -> f32 {
+    adc_to_volts(adc) / TEMP_SENSOR_GAIN - ZEROK
+}
+
+/// Convert raw adc code of the ADC supply diagnostic to the supply voltage.
+pub fn adc_to_supply(adc: u32) -> f32 {
+    adc_to_volts(adc) * SUPPLY_DIVIDER
 }
 
 /// Convert TEC drive current to dac code.
@@ -53,6 +86,24 @@ pub fn dac_to_i(val: u32) -> f32 {
     (v - VREF_TEC) / (10.0 * R_SENSE)
 }
 
+/// Convert the TEC driver current monitor output to TEC current.
+///
+/// # Args
+/// * `itec` - The current monitor (ITEC) output voltage.
+/// * `vref` - The measured TEC driver reference voltage.
+pub fn itec_to_i(itec: f32, vref: f32) -> f32 {
+    (itec - vref) / (ITEC_GAIN * R_SENSE)
+}
+
+/// Convert the TEC driver voltage monitor output to TEC voltage.
+///
+/// # Args
+/// * `vtec` - The voltage monitor (VDIFF) output voltage.
+/// * `vref` - The measured TEC driver reference voltage.
+pub fn vtec_to_v(vtec: f32, vref: f32) -> f32 {
+    (vtec - vref) * VTEC_GAIN
+}
+
 /// Convert maximum current to relative pulsewidth for the (analog voltage)
 /// max output current inputs of the TEC driver.
 pub fn i_to_pwm(i: f32) -> f32 {
@@ -67,7 +118,14 @@ pub fn v_to_pwm(v: f32) -> f32 {
 
 /// Convert a temperature in °C to an effective adc code. This can be used to
 /// compute an effective input iir offset.
-pub fn temp_to_iiroffset(temp: f32) -> f32 {
+///
+/// # Args
+/// * `temp` - The (corrected) temperature in °C.
+/// * `calibration` - The correction of the channel against a reference thermometer.
+pub fn temp_to_iiroffset(temp: f32, calibration: &TemperatureCalibration) -> f32 {
+    // Undo the correction
+    let temp = (temp - calibration.offset) / calibration.gain;
+
     // T (°C) to R
     let t_inv = 1.0 / (temp + ZEROK);
     let r = R_N * (B * (t_inv - T_N_INV)).exp();